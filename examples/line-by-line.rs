@@ -7,7 +7,7 @@ use tokio::prelude::future::ok;
 use tokio::prelude::{Future, Stream};
 use tokio_codec::{FramedRead, FramedWrite, LinesCodec};
 
-fn async_op(input: String) -> Box<Future<Item = String, Error = ()> + Send> {
+fn async_op(input: String) -> Box<dyn Future<Item = String, Error = ()> + Send> {
   Box::new(ok(input.to_ascii_uppercase()))
 }
 
@@ -0,0 +1,504 @@
+//! Compressed stdin and stdout, (de)compressed on the worker threads
+
+use bytes::{Bytes, BytesMut};
+use futures::{Async, Future, Poll};
+use std::io::{Cursor, Error, ErrorKind, Read, Result, SeekFrom, Write};
+use {
+    seek, threaded_reader_impl, Control, Incoming, Outgoing, StdinSource, ThreadedStdin,
+    ThreadedStdout, BUFSIZ,
+};
+
+/// Compression format. Each one requires the cargo feature of the same name (`xz` for `Xz` and so on).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip, possibly several concatenated members, like `zcat` reads
+    Gzip,
+    /// Zstandard
+    Zstd,
+    /// xz (LZMA2)
+    Xz,
+    /// bzip2
+    Bzip2,
+}
+
+impl Compression {
+    /// Format with magic bytes at the beginning of `data`, if it is enabled
+    fn detect(data: &[u8]) -> Option<Compression> {
+        let formats: &[(Compression, &[u8])] = &[
+            (Compression::Gzip, b"\x1f\x8b"),
+            (Compression::Zstd, b"\x28\xb5\x2f\xfd"),
+            (Compression::Xz, b"\xfd7zXZ\x00"),
+            (Compression::Bzip2, b"BZh"),
+        ];
+        formats
+            .iter()
+            .find(|&&(format, magic)| format.is_enabled() && data.starts_with(magic))
+            .map(|&(format, _)| format)
+    }
+
+    /// Whether support for this format is compiled in
+    pub fn is_enabled(self) -> bool {
+        match self {
+            Compression::Gzip => cfg!(feature = "gzip"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+            Compression::Xz => cfg!(feature = "xz"),
+            Compression::Bzip2 => cfg!(feature = "bzip2"),
+        }
+    }
+
+    fn decoder<R: Read + 'static>(self, r: R) -> Result<Box<dyn Read>> {
+        #[allow(unreachable_patterns)]
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Ok(Box::new(::flate2::read::MultiGzDecoder::new(r))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Box::new(::zstd::stream::read::Decoder::new(r)?)),
+            #[cfg(feature = "xz")]
+            Compression::Xz => Ok(Box::new(::xz2::read::XzDecoder::new_multi_decoder(r))),
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => Ok(Box::new(::bzip2::read::MultiBzDecoder::new(r))),
+            _ => {
+                drop(r);
+                Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "compression format is not enabled",
+                ))
+            }
+        }
+    }
+}
+
+/// Compressing writer which can give the underlying writer back after writing the trailer
+pub(crate) trait Encoder<W>: Write {
+    fn finish(self: Box<Self>) -> Result<W>;
+}
+
+#[cfg(feature = "gzip")]
+impl<W: Write> Encoder<W> for ::flate2::write::GzEncoder<W> {
+    fn finish(self: Box<Self>) -> Result<W> {
+        (*self).finish()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<W: Write> Encoder<W> for ::zstd::stream::write::Encoder<'static, W> {
+    fn finish(self: Box<Self>) -> Result<W> {
+        (*self).finish()
+    }
+}
+
+#[cfg(feature = "xz")]
+impl<W: Write> Encoder<W> for ::xz2::write::XzEncoder<W> {
+    fn finish(self: Box<Self>) -> Result<W> {
+        (*self).finish()
+    }
+}
+
+#[cfg(feature = "bzip2")]
+impl<W: Write> Encoder<W> for ::bzip2::write::BzEncoder<W> {
+    fn finish(self: Box<Self>) -> Result<W> {
+        (*self).finish()
+    }
+}
+
+impl Compression {
+    /// Fail for compression levels the encoder would reject or panic on
+    fn check_level(self, level: u32) -> Result<()> {
+        let (min, max) = match self {
+            Compression::Gzip | Compression::Xz => (0, 9),
+            Compression::Zstd => (1, 22),
+            Compression::Bzip2 => (1, 9),
+        };
+        if level < min || level > max {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{:?} compression level must be {} to {}, not {}",
+                    self, min, max, level
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn encoder<W: Write + 'static>(self, w: W, level: u32) -> Result<Box<dyn Encoder<W>>> {
+        #[allow(unreachable_patterns)]
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Ok(Box::new(::flate2::write::GzEncoder::new(
+                w,
+                ::flate2::Compression::new(level),
+            ))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Box::new(::zstd::stream::write::Encoder::new(
+                w,
+                level as i32,
+            )?)),
+            #[cfg(feature = "xz")]
+            Compression::Xz => Ok(Box::new(::xz2::write::XzEncoder::new(w, level))),
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => Ok(Box::new(::bzip2::write::BzEncoder::new(
+                w,
+                ::bzip2::Compression::new(level),
+            ))),
+            _ => {
+                let _ = (w, level);
+                Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "compression format is not enabled",
+                ))
+            }
+        }
+    }
+}
+
+/// Writer used by the stdout worker thread, which may be switched to compression on the fly
+pub(crate) enum Output<W> {
+    Plain(W),
+    Compressed(Box<dyn Encoder<W>>),
+}
+
+impl<W: Write + 'static> Output<W> {
+    pub(crate) fn compress(self, format: Compression, level: u32) -> Result<Output<W>> {
+        match self {
+            Output::Plain(w) => Ok(Output::Compressed(format.encoder(w, level)?)),
+            x => Ok(x),
+        }
+    }
+
+    /// Flush after each chunk for interactive outputs. Compressed output is only flushed on request.
+    pub(crate) fn flush_chunk(&mut self) -> Result<()> {
+        match *self {
+            Output::Plain(ref mut w) => w.flush(),
+            Output::Compressed(_) => Ok(()),
+        }
+    }
+
+    /// Write the trailer of compressed stream
+    pub(crate) fn finish(self) -> Result<W> {
+        match self {
+            Output::Plain(w) => Ok(w),
+            Output::Compressed(e) => e.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match *self {
+            Output::Plain(ref mut w) => w.write(buf),
+            Output::Compressed(ref mut w) => w.write(buf),
+        }
+    }
+    fn flush(&mut self) -> Result<()> {
+        match *self {
+            Output::Plain(ref mut w) => w.flush(),
+            Output::Compressed(ref mut w) => w.flush(),
+        }
+    }
+}
+
+/// Reader used by the worker thread, which may be switched to decompression on the fly
+pub(crate) enum Source<R> {
+    Plain(R),
+    Decompressed(Box<dyn Read>),
+}
+
+impl<R: seek::MaybeSeek + 'static> Source<R> {
+    /// Decompress `prefix` (data already sent to the async side) followed by the rest of the input
+    pub(crate) fn decompress(self, format: Compression, prefix: Bytes) -> Result<Source<R>> {
+        match self {
+            Source::Plain(r) => Ok(Source::Decompressed(
+                format.decoder(Cursor::new(prefix).chain(r))?,
+            )),
+            Source::Decompressed(_) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "stdin is already being decompressed",
+            )),
+        }
+    }
+}
+
+impl<R: Read> Read for Source<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match *self {
+            Source::Plain(ref mut r) => r.read(buf),
+            Source::Decompressed(ref mut r) => r.read(buf),
+        }
+    }
+}
+
+impl<R: seek::MaybeSeek> seek::MaybeSeek for Source<R> {
+    fn seek_to(&mut self, pos: SeekFrom) -> Result<u64> {
+        match *self {
+            Source::Plain(ref mut r) => r.seek_to(pos),
+            Source::Decompressed(_) => Err(seek::not_seekable()),
+        }
+    }
+}
+
+/// Future returned by `ThreadedStdin::auto_decompress`
+pub struct AutoDecompress {
+    inner: Option<ThreadedStdin>,
+    format: Option<Compression>,
+    /// Compressed data which reached the async side before the worker thread switched
+    prefix: BytesMut,
+}
+
+/// Magic of compressed data at the beginning of input
+fn sniff(stdin: &mut ThreadedStdin) -> Result<Option<Compression>> {
+    let mut magic = [0; 6];
+    let n = stdin.peek(&mut magic)?;
+    Ok(Compression::detect(&magic[..n]))
+}
+
+/// `stdin_mmap` has no thread to decompress on; read the mapping from a new one
+#[cfg(unix)]
+fn threaded(stdin: &mut ThreadedStdin) {
+    let queue_size = match stdin.src {
+        StdinSource::Mapped(ref m) => m.queue_size,
+        _ => return,
+    };
+    // The mapping can only be taken out of `stdin` by putting the worker in its place,
+    // so it is sent to the worker after that
+    let (snd, rcv) = ::std::sync::mpsc::channel();
+    let w = threaded_reader_impl(queue_size, BUFSIZ, None, move || {
+        seek::NotSeekable(rcv.recv().expect("mapping is sent to the worker"))
+    });
+    stdin.activity = w.activity.clone();
+    if let StdinSource::Mapped(m) = ::std::mem::replace(&mut stdin.src, StdinSource::Threaded(w)) {
+        let _ = snd.send(m);
+    }
+}
+
+impl Future for AutoDecompress {
+    type Item = (ThreadedStdin, Option<Compression>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(ThreadedStdin, Option<Compression>), Error> {
+        if self.format.is_none() {
+            let sniffed = sniff(
+                self.inner
+                    .as_mut()
+                    .expect("polled AutoDecompress after completion"),
+            );
+            let format = match sniffed {
+                Ok(Some(x)) => x,
+                Ok(None) => return Ok(Async::Ready((self.inner.take().unwrap(), None))),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            };
+            let mut stdin = self.inner.take().unwrap();
+            if let Some(debt) = stdin.debt.take() {
+                self.prefix.extend_from_slice(&debt);
+            }
+            stdin.pending_eof = false;
+            #[cfg(unix)]
+            threaded(&mut stdin);
+            if let StdinSource::Threaded(ref mut w) = stdin.src {
+                if w.ctl.send(Control::Decompress(format)).is_err() {
+                    return Err(ErrorKind::BrokenPipe.into());
+                }
+                w.seekable = false;
+            }
+            self.inner = Some(stdin);
+            self.format = Some(format);
+        }
+        loop {
+            let msg = {
+                let stdin = self
+                    .inner
+                    .as_mut()
+                    .expect("polled AutoDecompress after completion");
+                let w = match stdin.src {
+                    StdinSource::Threaded(ref mut w) => w,
+                    _ => return Err(ErrorKind::BrokenPipe.into()),
+                };
+                match w.recv() {
+                    Ok(Async::Ready(Some(x))) => x,
+                    Ok(Async::Ready(None)) => return Err(ErrorKind::BrokenPipe.into()),
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(_) => return Err(ErrorKind::Other.into()),
+                }
+            };
+            match msg {
+                // Empty chunks are end of file markers, which are no longer true
+                Incoming::Data(x) => self.prefix.extend_from_slice(&x),
+                Incoming::Switched => break,
+                Incoming::Error(e) => return Err(e),
+                Incoming::Eof | Incoming::Seeked(_) => (),
+            }
+        }
+        let mut stdin = self.inner.take().unwrap();
+        if let StdinSource::Threaded(ref mut w) = stdin.src {
+            let prefix = self.prefix.take().freeze();
+            if w.ctl.send(Control::Prefix(prefix)).is_err() {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            w.pos = 0;
+        }
+        Ok(Async::Ready((stdin, self.format)))
+    }
+}
+
+impl ThreadedStdin {
+    /// Detect compressed input by its magic bytes and decompress it on the worker thread.
+    ///
+    /// Resolves to the same stdin, now delivering decompressed data (and no longer seekable),
+    /// and the detected format. Uncompressed input (or a format whose cargo feature is off)
+    /// is left as is. With `stdin_mmap`, a worker thread is started for decompression.
+    pub fn auto_decompress(self) -> AutoDecompress {
+        AutoDecompress {
+            inner: Some(self),
+            format: None,
+            prefix: BytesMut::new(),
+        }
+    }
+}
+
+impl ThreadedStdout {
+    /// Compress everything written after this call on the worker thread.
+    ///
+    /// `level` means what it means for the format: 0-9 for gzip and xz, 1-9 for bzip2
+    /// and 1-22 for zstd. Other levels fail with `ErrorKind::InvalidInput`.
+    /// `flush` does a sync flush of the compressor, so that everything written so far can be decompressed;
+    /// `shutdown` writes the trailer. Compression can not be turned off once enabled.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if the cargo feature for `format` is not enabled.
+    /// Errors of the compressor are reported by the following write, flush or shutdown.
+    pub fn compress(mut self, format: Compression, level: u32) -> Result<ThreadedStdout> {
+        if !format.is_enabled() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "compression format is not enabled",
+            ));
+        }
+        format.check_level(level)?;
+        // A fresh clone of the sender always has room for one message
+        if self
+            .snd
+            .clone()
+            .try_send(Outgoing::Compress(format, level))
+            .is_err()
+        {
+            return Err(self.worker_error());
+        }
+        self.dirty = true;
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Compression;
+    use futures::future::poll_fn;
+    use futures::Future;
+    use std::io::Read;
+    use std::time::Duration;
+    use testutil::{block_on, chunk_reader, chunks_reader};
+    use threaded_reader;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn detect() {
+        let cases: &[(&[u8], Compression, bool)] = &[
+            (b"\x1f\x8b", Compression::Gzip, cfg!(feature = "gzip")),
+            (
+                b"\x28\xb5\x2f\xfd",
+                Compression::Zstd,
+                cfg!(feature = "zstd"),
+            ),
+            (b"\xfd7zXZ\x00", Compression::Xz, cfg!(feature = "xz")),
+            (b"BZh", Compression::Bzip2, cfg!(feature = "bzip2")),
+        ];
+        for &(data, format, enabled) in cases {
+            let expected = if enabled { Some(format) } else { None };
+            assert_eq!(Compression::detect(data), expected);
+            assert_eq!(Compression::detect(&data[..data.len() - 1]), None);
+        }
+        assert_eq!(Compression::detect(b""), None);
+    }
+
+    #[test]
+    fn levels() {
+        assert!(Compression::Gzip.check_level(0).is_ok());
+        assert!(Compression::Zstd.check_level(22).is_ok());
+        let e = Compression::Bzip2.check_level(0).unwrap_err();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+        let e = Compression::Xz.check_level(10).unwrap_err();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn plain_input() {
+        let s = threaded_reader(chunks_reader(&[b"\x1f", b"plain"]), 1);
+        let (mut s, format) = s.auto_decompress().wait().unwrap();
+        assert_eq!(format, None);
+        let mut out = vec![];
+        block_on(|| s.read_to_end(&mut out)).unwrap();
+        assert_eq!(out, b"\x1fplain");
+    }
+
+    #[test]
+    fn timeout_keeps_stdin() {
+        let (snd, r) = chunk_reader();
+        let s = threaded_reader(r, 1).with_timeout(Duration::from_millis(50));
+        let mut f = s.auto_decompress();
+        let mut rt = Runtime::new().unwrap();
+        match rt.block_on(poll_fn(|| f.poll())) {
+            Err(e) => assert_eq!(e.kind(), ::std::io::ErrorKind::TimedOut),
+            Ok(_) => panic!("expected a timeout"),
+        }
+        snd.send(b"late".to_vec()).unwrap();
+        drop(snd);
+        let (mut s, format) = rt.block_on(poll_fn(|| f.poll())).unwrap();
+        assert_eq!(format, None);
+        let mut out = vec![];
+        block_on(|| s.read_to_end(&mut out)).unwrap();
+        assert_eq!(out, b"late");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_input() {
+        use std::io::Write;
+
+        let mut enc = ::flate2::write::GzEncoder::new(vec![], ::flate2::Compression::default());
+        enc.write_all(b"hello, world").unwrap();
+        let gz = enc.finish().unwrap();
+        // The magic is split between chunks
+        let s = threaded_reader(chunks_reader(&[&gz[..1], &gz[1..5], &gz[5..]]), 1);
+        let (mut s, format) = s.auto_decompress().wait().unwrap();
+        assert_eq!(format, Some(Compression::Gzip));
+        assert!(!s.is_seekable());
+        let mut text = vec![];
+        block_on(|| s.read_to_end(&mut text)).unwrap();
+        assert_eq!(text, b"hello, world");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_output() {
+        use futures::future::poll_fn;
+        use std::io::Write;
+        use testutil::SharedBuf;
+        use tokio_io::AsyncWrite;
+        use StdioKind;
+
+        let out = SharedBuf::default();
+        let out_ = out.clone();
+        let mut w = ::threaded_writer(4, StdioKind::Pipe, move || out_);
+        block_on(|| w.write(b"plain ")).unwrap();
+        let mut w = w.compress(Compression::Gzip, 6).unwrap();
+        block_on(|| w.write(b"hello, ")).unwrap();
+        block_on(|| w.write(b"world")).unwrap();
+        poll_fn(|| w.shutdown()).wait().unwrap();
+        let data = out.contents();
+        assert!(data.starts_with(b"plain \x1f\x8b"));
+        let mut text = vec![];
+        ::flate2::read::GzDecoder::new(&data[6..])
+            .read_to_end(&mut text)
+            .unwrap();
+        assert_eq!(text, b"hello, world");
+    }
+}
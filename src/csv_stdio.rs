@@ -0,0 +1,416 @@
+//! CSV and TSV records over stdin and stdout (requires `csv` cargo feature)
+//!
+//! ```rust,no_run
+//! extern crate futures;
+//! extern crate tokio;
+//! extern crate tokio_stdin_stdout;
+//!
+//! use futures::{Future, Stream};
+//!
+//! // Convert TSV with a header line to CSV without one
+//! let input = tokio_stdin_stdout::stdin(0).csv::<Vec<String>>().delimiter(b'\t');
+//! let output = tokio_stdin_stdout::stdout(0).csv().has_headers(false);
+//! let f = input.forward(output).map(|_| ()).map_err(|e| eprintln!("{}", e));
+//! tokio::runtime::current_thread::Runtime::new().unwrap().block_on(f).unwrap();
+//! ```
+
+use bytes::Bytes;
+use csv::{ByteRecord, Position, StringRecord};
+use csv_core::ReadRecordResult;
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+use tokio_io::AsyncWrite;
+use {ThreadedStdin, ThreadedStdout};
+
+pub use csv::QuoteStyle;
+
+/// Stream of records read from stdin. Created by `ThreadedStdin::csv`.
+///
+/// Quoted fields may contain delimiters and newlines and may span any number of chunks.
+/// Records which are not valid UTF-8 or do not deserialize into `T` are reported as
+/// `ErrorKind::InvalidData` with a `csv::FromUtf8Error` or `csv::Error` inside.
+/// Records with a different number of fields than the first one are rejected,
+/// unless allowed with `flexible(true)`.
+pub struct CsvStdin<T> {
+    inner: ThreadedStdin,
+    builder: csv_core::ReaderBuilder,
+    rdr: csv_core::Reader,
+    has_headers: bool,
+    flexible: bool,
+    headers: Option<StringRecord>,
+    /// Number of fields in the first record
+    fields: Option<usize>,
+    /// Unparsed remainder of the last chunk
+    chunk: Bytes,
+    eof: bool,
+    /// Fields of the record being parsed and their end offsets
+    out: Vec<u8>,
+    outlen: usize,
+    ends: Vec<usize>,
+    endlen: usize,
+    /// Bytes consumed so far and position of the record being parsed
+    offset: u64,
+    start: Option<Position>,
+    records: u64,
+    _pd: PhantomData<fn() -> T>,
+}
+
+impl<T> CsvStdin<T> {
+    fn new(inner: ThreadedStdin) -> CsvStdin<T> {
+        let builder = csv_core::ReaderBuilder::new();
+        CsvStdin {
+            inner,
+            rdr: builder.build(),
+            builder,
+            has_headers: true,
+            flexible: false,
+            headers: None,
+            fields: None,
+            chunk: Bytes::new(),
+            eof: false,
+            out: vec![0; 1024],
+            outlen: 0,
+            ends: vec![0; 16],
+            endlen: 0,
+            offset: 0,
+            start: None,
+            records: 0,
+            _pd: PhantomData,
+        }
+    }
+
+    fn configure<F: FnOnce(&mut csv_core::ReaderBuilder)>(mut self, f: F) -> CsvStdin<T> {
+        f(&mut self.builder);
+        self.rdr = self.builder.build();
+        self
+    }
+
+    /// Field delimiter, `b','` by default. Use `b'\t'` for TSV.
+    pub fn delimiter(self, delimiter: u8) -> CsvStdin<T> {
+        self.configure(|b| {
+            b.delimiter(delimiter);
+        })
+    }
+    /// Quote character, `b'"'` by default
+    pub fn quote(self, quote: u8) -> CsvStdin<T> {
+        self.configure(|b| {
+            b.quote(quote);
+        })
+    }
+    /// Whether quotes are special at all. Enabled by default.
+    pub fn quoting(self, yes: bool) -> CsvStdin<T> {
+        self.configure(|b| {
+            b.quoting(yes);
+        })
+    }
+    /// Whether the first record is a header. Enabled by default.
+    ///
+    /// Headers are not returned from the stream, but are used to deserialize structs and maps.
+    pub fn has_headers(mut self, yes: bool) -> CsvStdin<T> {
+        self.has_headers = yes;
+        self
+    }
+    /// Whether records may have different numbers of fields. Disabled by default,
+    /// like in `csv::ReaderBuilder`.
+    ///
+    /// When disabled, a record with a different number of fields than the first one
+    /// (which may be the header) is an `ErrorKind::InvalidData` error.
+    pub fn flexible(mut self, yes: bool) -> CsvStdin<T> {
+        self.flexible = yes;
+        self
+    }
+    /// The header record, once it has been read
+    pub fn headers(&self) -> Option<&StringRecord> {
+        self.headers.as_ref()
+    }
+
+    /// Next raw record, or `None` at the end of input
+    fn poll_record(&mut self) -> Poll<Option<StringRecord>, Error> {
+        loop {
+            if self.chunk.is_empty() && !self.eof {
+                match try_ready!(self.inner.poll()) {
+                    Some(x) => self.chunk = x,
+                    None => self.eof = true,
+                }
+            }
+            if self.start.is_none() {
+                let mut pos = Position::new();
+                pos.set_byte(self.offset)
+                    .set_line(self.rdr.line())
+                    .set_record(self.records);
+                self.start = Some(pos);
+            }
+            let (res, nin, nout, nend) = self.rdr.read_record(
+                &self.chunk,
+                &mut self.out[self.outlen..],
+                &mut self.ends[self.endlen..],
+            );
+            self.chunk.advance(nin);
+            self.offset += nin as u64;
+            self.outlen += nout;
+            self.endlen += nend;
+            match res {
+                ReadRecordResult::InputEmpty => (),
+                ReadRecordResult::OutputFull => {
+                    let len = self.out.len() * 2;
+                    self.out.resize(len, 0);
+                }
+                ReadRecordResult::OutputEndsFull => {
+                    let len = self.ends.len() * 2;
+                    self.ends.resize(len, 0);
+                }
+                ReadRecordResult::Record => return Ok(Async::Ready(Some(self.take_record()?))),
+                ReadRecordResult::End => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+
+    fn take_record(&mut self) -> Result<StringRecord, Error> {
+        let mut rec = ByteRecord::with_capacity(self.outlen, self.endlen);
+        let mut begin = 0;
+        for &end in &self.ends[..self.endlen] {
+            rec.push_field(&self.out[begin..end]);
+            begin = end;
+        }
+        let pos = self.start.take();
+        let fields = self.endlen;
+        self.outlen = 0;
+        self.endlen = 0;
+        self.records += 1;
+        match self.fields {
+            None => self.fields = Some(fields),
+            Some(first) if !self.flexible && first != fields => {
+                let line = pos.as_ref().map_or(0, |p| p.line());
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "CSV record {} (line {}) has {} fields, but the first record has {}",
+                        self.records - 1,
+                        line,
+                        fields,
+                        first
+                    ),
+                ));
+            }
+            Some(_) => (),
+        }
+        rec.set_position(pos);
+        StringRecord::from_byte_record(rec).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+impl<T: DeserializeOwned> Stream for CsvStdin<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<T>, Error> {
+        loop {
+            let rec = match try_ready!(self.poll_record()) {
+                Some(x) => x,
+                None => return Ok(Async::Ready(None)),
+            };
+            if self.has_headers && self.headers.is_none() {
+                self.headers = Some(rec);
+                continue;
+            }
+            return match rec.deserialize(self.headers.as_ref()) {
+                Ok(x) => Ok(Async::Ready(Some(x))),
+                Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+            };
+        }
+    }
+}
+
+/// Sink of records written to stdout. Created by `ThreadedStdout::csv`.
+///
+/// Each record (together with the header line for the first one) is sent
+/// to the worker thread as a single chunk. Up to one record is buffered here when the queue is full.
+/// Records may have different numbers of fields.
+pub struct CsvStdout<T> {
+    inner: ThreadedStdout,
+    /// Builds a writer for each record; headers are turned off after the first one
+    builder: csv::WriterBuilder,
+    /// Serialized record which did not fit into the queue yet
+    buffered: Option<Bytes>,
+    _pd: PhantomData<fn(T)>,
+}
+
+impl<T> CsvStdout<T> {
+    fn new(inner: ThreadedStdout) -> CsvStdout<T> {
+        let mut builder = csv::WriterBuilder::new();
+        builder.flexible(true);
+        CsvStdout {
+            inner,
+            builder,
+            buffered: None,
+            _pd: PhantomData,
+        }
+    }
+
+    fn configure<F: FnOnce(&mut csv::WriterBuilder)>(mut self, f: F) -> CsvStdout<T> {
+        f(&mut self.builder);
+        self
+    }
+
+    /// Field delimiter, `b','` by default. Use `b'\t'` for TSV.
+    pub fn delimiter(self, delimiter: u8) -> CsvStdout<T> {
+        self.configure(|b| {
+            b.delimiter(delimiter);
+        })
+    }
+    /// Quote character, `b'"'` by default
+    pub fn quote(self, quote: u8) -> CsvStdout<T> {
+        self.configure(|b| {
+            b.quote(quote);
+        })
+    }
+    /// When to quote fields, `QuoteStyle::Necessary` by default
+    pub fn quote_style(self, style: QuoteStyle) -> CsvStdout<T> {
+        self.configure(|b| {
+            b.quote_style(style);
+        })
+    }
+    /// Whether to write a header line derived from field names of the first record
+    /// (for structs). Enabled by default.
+    pub fn has_headers(self, yes: bool) -> CsvStdout<T> {
+        self.configure(|b| {
+            b.has_headers(yes);
+        })
+    }
+
+    /// Get the underlying `ThreadedStdout` back
+    pub fn into_inner(self) -> ThreadedStdout {
+        self.inner
+    }
+}
+
+impl<T: Serialize> Sink for CsvStdout<T> {
+    type SinkItem = T;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: T) -> StartSend<T, Error> {
+        if !self.inner.send_pending(&mut self.buffered)? {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        let mut out = Vec::new();
+        {
+            let mut wtr = self.builder.from_writer(&mut out);
+            wtr.serialize(&item)
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            wtr.flush()?;
+        }
+        self.builder.has_headers(false);
+        self.buffered = Some(Bytes::from(out));
+        self.inner.send_pending(&mut self.buffered)?;
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        if !self.inner.send_pending(&mut self.buffered)? {
+            return Ok(Async::NotReady);
+        }
+        self.inner.poll_complete_chunks()
+    }
+
+    fn close(&mut self) -> Poll<(), Error> {
+        try_ready!(self.poll_complete());
+        self.inner.shutdown()
+    }
+}
+
+impl ThreadedStdin {
+    /// Turn into a stream of CSV records, deserialized into `T`,
+    /// e.g. `Vec<String>`, a tuple or a struct with `#[derive(Deserialize)]`.
+    ///
+    /// By default the first record is a header; see `CsvStdin` methods for other options.
+    pub fn csv<T: DeserializeOwned>(self) -> CsvStdin<T> {
+        CsvStdin::new(self)
+    }
+}
+
+impl ThreadedStdout {
+    /// Turn into a sink of CSV records, serialized from `T`
+    pub fn csv<T: Serialize>(self) -> CsvStdout<T> {
+        CsvStdout::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::poll_fn;
+    use futures::{Future, Sink, Stream};
+    use std::io::ErrorKind;
+    use testutil::{chunks_reader, SharedBuf};
+    use {threaded_reader, StdioKind};
+
+    #[test]
+    fn quoted_field_across_chunks() {
+        let input: &[&[u8]] = &[b"name,n\n\"x,", b"y\n", b"z\"\"\",2\nw", b",3"];
+        let s = threaded_reader(chunks_reader(input), 1).csv::<(String, u32)>();
+        let records: Vec<(String, u32)> = s.wait().map(|x| x.unwrap()).collect();
+        assert_eq!(records, [("x,y\nz\"".to_string(), 2), ("w".to_string(), 3)]);
+    }
+
+    #[test]
+    fn tsv_without_headers() {
+        let input: &[&[u8]] = &[b"a\tb\n", b"c\t\"d\te\"\n"];
+        let mut s = threaded_reader(chunks_reader(input), 1)
+            .csv::<Vec<String>>()
+            .delimiter(b'\t')
+            .has_headers(false);
+        let records: Vec<Vec<String>> = s.by_ref().wait().map(|x| x.unwrap()).collect();
+        assert_eq!(records, [["a", "b"], ["c", "d\te"]]);
+        assert!(s.headers().is_none());
+    }
+
+    #[test]
+    fn bad_record() {
+        let input: &[&[u8]] = &[b"n\n1\nx\n2\n"];
+        let r: Vec<_> = threaded_reader(chunks_reader(input), 1)
+            .csv::<(u32,)>()
+            .wait()
+            .collect();
+        assert_eq!(r.len(), 3);
+        assert_eq!(r[0].as_ref().unwrap(), &(1,));
+        assert_eq!(r[1].as_ref().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(r[2].as_ref().unwrap(), &(2,));
+    }
+
+    #[test]
+    fn field_count() {
+        let input: &[&[u8]] = &[b"a,b\n1,2\n3\n4,5\n"];
+        let s = threaded_reader(chunks_reader(input), 1)
+            .csv::<Vec<String>>()
+            .flexible(true);
+        let r: Vec<Vec<String>> = s.wait().map(|x| x.unwrap()).collect();
+        assert_eq!(r, [vec!["1", "2"], vec!["3"], vec!["4", "5"]]);
+
+        let s = threaded_reader(chunks_reader(input), 1).csv::<Vec<String>>();
+        let r: Vec<_> = s.wait().collect();
+        assert_eq!(r.len(), 3);
+        let e = r[1].as_ref().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            e.to_string(),
+            "CSV record 2 (line 3) has 1 fields, but the first record has 2"
+        );
+        assert_eq!(r[2].as_ref().unwrap(), &["4", "5"]);
+    }
+
+    #[test]
+    fn sink() {
+        let out = SharedBuf::default();
+        let out_ = out.clone();
+        let mut sink = ::threaded_writer(4, StdioKind::Pipe, move || out_).csv();
+        sink = sink.send(vec!["a", "x,y"]).wait().unwrap();
+        sink = sink.send(vec!["line\nbreak", "\"q\""]).wait().unwrap();
+        poll_fn(|| sink.close()).wait().unwrap();
+        assert_eq!(
+            String::from_utf8(out.contents()).unwrap(),
+            "a,\"x,y\"\n\"line\nbreak\",\"\"\"q\"\"\"\n"
+        );
+    }
+}
@@ -0,0 +1,469 @@
+//! Decoding of terminal key sequences
+
+use futures::{Async, Future, Poll, Stream};
+use mouse::{self, MouseEvent};
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+use ThreadedStdin;
+
+/// Modifier keys held down together with a key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Modifiers {
+    /// Shift
+    pub shift: bool,
+    /// Alt (Meta)
+    pub alt: bool,
+    /// Control
+    pub ctrl: bool,
+}
+
+impl Modifiers {
+    /// `xterm`-style modifier parameter, like the `5` in `ESC [ 1 ; 5 A`
+    fn from_param(p: u32) -> Modifiers {
+        let bits = p.saturating_sub(1);
+        Modifiers {
+            shift: bits & 1 != 0,
+            alt: bits & 2 != 0,
+            ctrl: bits & 4 != 0,
+        }
+    }
+}
+
+/// Key on the keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyCode {
+    /// Character; with Ctrl pressed, letters are reported lowercase
+    Char(char),
+    /// Enter (Return)
+    Enter,
+    /// Tab; Shift-Tab is reported as Tab with `shift`
+    Tab,
+    /// Backspace
+    Backspace,
+    /// Escape
+    Esc,
+    /// Up arrow
+    Up,
+    /// Down arrow
+    Down,
+    /// Left arrow
+    Left,
+    /// Right arrow
+    Right,
+    /// Home
+    Home,
+    /// End
+    End,
+    /// Page Up
+    PageUp,
+    /// Page Down
+    PageDown,
+    /// Insert
+    Insert,
+    /// Delete
+    Delete,
+    /// Function key, `F(1)` to `F(12)`
+    F(u8),
+}
+
+/// Key press with modifiers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyPress {
+    /// Key pressed
+    pub code: KeyCode,
+    /// Modifiers held down
+    pub modifiers: Modifiers,
+}
+
+/// Event decoded from terminal input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputEvent {
+    /// Key press
+    Key(KeyPress),
+    /// Beginning of pasted text (`ESC [ ? 2004 h` must be written to get these)
+    PasteStart,
+    /// End of pasted text
+    PasteEnd,
+    /// Terminal window got focus (`ESC [ ? 1004 h` must be written to get these)
+    FocusIn,
+    /// Terminal window lost focus
+    FocusOut,
+    /// Mouse report (see `ThreadedStdout::mouse_capture`)
+    Mouse(MouseEvent),
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Parsed {
+    Event(InputEvent, usize),
+    /// Unknown sequence of this length
+    Skip(usize),
+    Incomplete,
+}
+
+fn key(code: KeyCode, modifiers: Modifiers) -> InputEvent {
+    InputEvent::Key(KeyPress { code, modifiers })
+}
+
+/// Decode one event from the beginning of non-empty `buf`.
+/// With `force`, an incomplete sequence is taken as far as it goes, as no more bytes are coming soon.
+pub(crate) fn parse(buf: &[u8], force: bool) -> Parsed {
+    let none = Modifiers::default();
+    let ctrl = Modifiers { ctrl: true, ..none };
+    let ev = |code| Parsed::Event(key(code, none), 1);
+    match buf[0] {
+        0x1b => parse_escape(buf, force),
+        b'\r' | b'\n' => ev(KeyCode::Enter),
+        b'\t' => ev(KeyCode::Tab),
+        0x7f => ev(KeyCode::Backspace),
+        0x00 => Parsed::Event(key(KeyCode::Char(' '), ctrl), 1),
+        b @ 0x01..=0x1a => Parsed::Event(key(KeyCode::Char((b'a' + b - 1) as char), ctrl), 1),
+        b @ 0x1c..=0x1f => Parsed::Event(key(KeyCode::Char((b'\\' + b - 0x1c) as char), ctrl), 1),
+        b => {
+            let len = match b {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            if buf.len() < len && !force {
+                return Parsed::Incomplete;
+            }
+            match ::std::str::from_utf8(&buf[..len.min(buf.len())]) {
+                Ok(s) => Parsed::Event(key(KeyCode::Char(s.chars().next().unwrap()), none), len),
+                Err(_) => Parsed::Skip(1),
+            }
+        }
+    }
+}
+
+/// How long to wait for the rest of an escape sequence before taking ESC as the Escape key
+pub(crate) const ESC_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Sequences longer than this without a final byte are garbage
+const MAX_SEQUENCE: usize = 32;
+
+fn parse_escape(buf: &[u8], force: bool) -> Parsed {
+    let esc = Parsed::Event(key(KeyCode::Esc, Modifiers::default()), 1);
+    let next = match buf.get(1) {
+        Some(&x) => x,
+        None if force => return esc,
+        None => return Parsed::Incomplete,
+    };
+    let parsed = match next {
+        b'[' => parse_csi(buf),
+        b'O' => match buf.get(2) {
+            Some(&c) => Some(match ss3_key(c) {
+                Some(code) => Parsed::Event(key(code, Modifiers::default()), 3),
+                None => Parsed::Skip(3),
+            }),
+            None => Some(Parsed::Incomplete),
+        },
+        _ => None,
+    };
+    match parsed {
+        Some(Parsed::Incomplete) if !force => Parsed::Incomplete,
+        Some(Parsed::Incomplete) | None => {
+            // Alt + key: ESC prefixes whatever follows
+            match parse(&buf[1..], force) {
+                Parsed::Event(InputEvent::Key(mut k), len) => {
+                    k.modifiers.alt = true;
+                    Parsed::Event(InputEvent::Key(k), len + 1)
+                }
+                Parsed::Event(_, _) | Parsed::Skip(_) => esc,
+                Parsed::Incomplete => Parsed::Incomplete,
+            }
+        }
+        Some(x) => x,
+    }
+}
+
+fn ss3_key(c: u8) -> Option<KeyCode> {
+    Some(match c {
+        b'A' => KeyCode::Up,
+        b'B' => KeyCode::Down,
+        b'C' => KeyCode::Right,
+        b'D' => KeyCode::Left,
+        b'H' => KeyCode::Home,
+        b'F' => KeyCode::End,
+        b'P' => KeyCode::F(1),
+        b'Q' => KeyCode::F(2),
+        b'R' => KeyCode::F(3),
+        b'S' => KeyCode::F(4),
+        _ => return None,
+    })
+}
+
+/// `ESC [ params final`. `None` if this is not a well-formed sequence.
+fn parse_csi(buf: &[u8]) -> Option<Parsed> {
+    if buf.get(2) == Some(&b'M') {
+        if buf.len() < 6 {
+            return Some(Parsed::Incomplete);
+        }
+        return Some(match mouse::parse_x10([buf[3], buf[4], buf[5]]) {
+            Some(ev) => Parsed::Event(InputEvent::Mouse(ev), 6),
+            None => Parsed::Skip(6),
+        });
+    }
+    let end = match buf[2..].iter().position(|&c| !(0x20..=0x3f).contains(&c)) {
+        Some(x) => x + 2,
+        None if buf.len() < MAX_SEQUENCE => return Some(Parsed::Incomplete),
+        None => return Some(Parsed::Skip(buf.len())),
+    };
+    let fin = buf[end];
+    if !(0x40..=0x7e).contains(&fin) {
+        return None;
+    }
+    let len = end + 1;
+    let sgr_mouse = buf[2] == b'<';
+    let params_start = if sgr_mouse { 3 } else { 2 };
+    let params: Vec<u32> = ::std::str::from_utf8(&buf[params_start..end])
+        .unwrap_or("")
+        .split(';')
+        .map(|p| p.parse().unwrap_or(0))
+        .collect();
+    let param = |i: usize| params.get(i).cloned().unwrap_or(0);
+    if sgr_mouse {
+        return Some(match mouse::parse_sgr(&params, fin) {
+            Some(ev) => Parsed::Event(InputEvent::Mouse(ev), len),
+            None => Parsed::Skip(len),
+        });
+    }
+    let mods = Modifiers::from_param(param(1));
+    let code = match fin {
+        b'I' if end == 2 => return Some(Parsed::Event(InputEvent::FocusIn, len)),
+        b'O' if end == 2 => return Some(Parsed::Event(InputEvent::FocusOut, len)),
+        b'Z' => {
+            let shift = Modifiers {
+                shift: true,
+                ..Modifiers::default()
+            };
+            return Some(Parsed::Event(key(KeyCode::Tab, shift), len));
+        }
+        b'~' => match param(0) {
+            1 | 7 => KeyCode::Home,
+            2 => KeyCode::Insert,
+            3 => KeyCode::Delete,
+            4 | 8 => KeyCode::End,
+            5 => KeyCode::PageUp,
+            6 => KeyCode::PageDown,
+            n @ 11..=15 => KeyCode::F((n - 10) as u8),
+            n @ 17..=21 => KeyCode::F((n - 11) as u8),
+            n @ 23..=24 => KeyCode::F((n - 12) as u8),
+            200 => return Some(Parsed::Event(InputEvent::PasteStart, len)),
+            201 => return Some(Parsed::Event(InputEvent::PasteEnd, len)),
+            _ => return Some(Parsed::Skip(len)),
+        },
+        c => match ss3_key(c) {
+            Some(code) => code,
+            None => return Some(Parsed::Skip(len)),
+        },
+    };
+    Some(Parsed::Event(key(code, mods), len))
+}
+
+/// Stream of events decoded from terminal input. Created by `ThreadedStdin::key_events`.
+///
+/// Put the terminal into raw mode (`ThreadedStdin::raw_mode`) to get keys as they are pressed.
+/// A lone ESC is reported as the Escape key if nothing follows it within
+/// the escape timeout, otherwise it marks the following key as pressed with Alt.
+pub struct KeyEvents {
+    inner: ThreadedStdin,
+    buf: Vec<u8>,
+    esc_timeout: Duration,
+    delay: Option<Delay>,
+    eof: bool,
+}
+
+impl KeyEvents {
+    /// How long to wait for the rest of an escape sequence, 50ms by default
+    pub fn esc_timeout(mut self, timeout: Duration) -> KeyEvents {
+        self.esc_timeout = timeout;
+        self
+    }
+
+    /// Get `ThreadedStdin` back. Bytes of an incomplete sequence are lost.
+    pub fn into_inner(self) -> ThreadedStdin {
+        self.inner
+    }
+
+    fn take(&mut self, force: bool) -> Option<InputEvent> {
+        while !self.buf.is_empty() {
+            match parse(&self.buf, force) {
+                Parsed::Event(ev, len) => {
+                    self.buf.drain(..len);
+                    return Some(ev);
+                }
+                Parsed::Skip(len) => {
+                    self.buf.drain(..len);
+                }
+                Parsed::Incomplete => return None,
+            }
+        }
+        None
+    }
+}
+
+impl Stream for KeyEvents {
+    type Item = InputEvent;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<InputEvent>, Error> {
+        loop {
+            if let Some(ev) = self.take(self.eof) {
+                return Ok(Async::Ready(Some(ev)));
+            }
+            if self.eof {
+                return Ok(Async::Ready(None));
+            }
+            match self.inner.poll()? {
+                Async::Ready(Some(chunk)) => {
+                    self.buf.extend_from_slice(&chunk);
+                    self.delay = None;
+                }
+                Async::Ready(None) => self.eof = true,
+                Async::NotReady => {
+                    if self.buf.is_empty() {
+                        return Ok(Async::NotReady);
+                    }
+                    let timeout = self.esc_timeout;
+                    let delay = self
+                        .delay
+                        .get_or_insert_with(|| Delay::new(Instant::now() + timeout));
+                    try_ready!(delay.poll().map_err(|e| Error::new(ErrorKind::Other, e)));
+                    self.delay = None;
+                    if let Some(ev) = self.take(true) {
+                        return Ok(Async::Ready(Some(ev)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl ThreadedStdin {
+    /// Turn into a stream of key presses and other terminal input events
+    pub fn key_events(self) -> KeyEvents {
+        KeyEvents {
+            inner: self,
+            buf: Vec::new(),
+            esc_timeout: ESC_TIMEOUT,
+            delay: None,
+            eof: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mouse::{MouseAction, MouseButton};
+
+    fn mods(shift: bool, alt: bool, ctrl: bool) -> Modifiers {
+        Modifiers { shift, alt, ctrl }
+    }
+
+    fn k(code: KeyCode, modifiers: Modifiers, len: usize) -> Parsed {
+        Parsed::Event(key(code, modifiers), len)
+    }
+
+    #[test]
+    fn keys() {
+        let none = Modifiers::default();
+        let alt = mods(false, true, false);
+        let ctrl = mods(false, false, true);
+        let table: &[(&[u8], bool, Parsed)] = &[
+            (b"a", false, k(KeyCode::Char('a'), none, 1)),
+            (b"\r", false, k(KeyCode::Enter, none, 1)),
+            (b"\t", false, k(KeyCode::Tab, none, 1)),
+            (b"\x7f", false, k(KeyCode::Backspace, none, 1)),
+            (b"\x00", false, k(KeyCode::Char(' '), ctrl, 1)),
+            (b"\x03", false, k(KeyCode::Char('c'), ctrl, 1)),
+            (b"\x1c", false, k(KeyCode::Char('\\'), ctrl, 1)),
+            ("é".as_bytes(), false, k(KeyCode::Char('é'), none, 2)),
+            (b"\xc3", false, Parsed::Incomplete),
+            (b"\xff", false, Parsed::Skip(1)),
+            // Alt prefix
+            (b"\x1bx", false, k(KeyCode::Char('x'), alt, 2)),
+            (
+                b"\x1b\x03",
+                false,
+                k(KeyCode::Char('c'), mods(false, true, true), 2),
+            ),
+            // ESC alone waits for more, unless forced
+            (b"\x1b", false, Parsed::Incomplete),
+            (b"\x1b", true, k(KeyCode::Esc, none, 1)),
+            (b"\x1b[", false, Parsed::Incomplete),
+            (b"\x1b[", true, k(KeyCode::Char('['), alt, 2)),
+            (b"\x1bO", false, Parsed::Incomplete),
+            (b"\x1bO", true, k(KeyCode::Char('O'), alt, 2)),
+            (b"\x1b\x1b", true, k(KeyCode::Esc, alt, 2)),
+            // SS3
+            (b"\x1bOA", false, k(KeyCode::Up, none, 3)),
+            (b"\x1bOP", false, k(KeyCode::F(1), none, 3)),
+            (b"\x1bOz", false, Parsed::Skip(3)),
+            // CSI
+            (b"\x1b[D", false, k(KeyCode::Left, none, 3)),
+            (b"\x1b[1;5C", false, k(KeyCode::Right, ctrl, 6)),
+            (
+                b"\x1b[1;2H",
+                false,
+                k(KeyCode::Home, mods(true, false, false), 6),
+            ),
+            (
+                b"\x1b[Z",
+                false,
+                k(KeyCode::Tab, mods(true, false, false), 3),
+            ),
+            (b"\x1b[1;3", false, Parsed::Incomplete),
+            (b"\x1b[q", false, Parsed::Skip(3)),
+            // `~` codes
+            (b"\x1b[1~", false, k(KeyCode::Home, none, 4)),
+            (b"\x1b[2~", false, k(KeyCode::Insert, none, 4)),
+            (b"\x1b[3;5~", false, k(KeyCode::Delete, ctrl, 6)),
+            (b"\x1b[4~", false, k(KeyCode::End, none, 4)),
+            (b"\x1b[5~", false, k(KeyCode::PageUp, none, 4)),
+            (b"\x1b[6~", false, k(KeyCode::PageDown, none, 4)),
+            (b"\x1b[15~", false, k(KeyCode::F(5), none, 5)),
+            (b"\x1b[17~", false, k(KeyCode::F(6), none, 5)),
+            (b"\x1b[24~", false, k(KeyCode::F(12), none, 5)),
+            (b"\x1b[99~", false, Parsed::Skip(5)),
+            // Focus and paste
+            (b"\x1b[I", false, Parsed::Event(InputEvent::FocusIn, 3)),
+            (b"\x1b[O", false, Parsed::Event(InputEvent::FocusOut, 3)),
+            (
+                b"\x1b[200~",
+                false,
+                Parsed::Event(InputEvent::PasteStart, 6),
+            ),
+            (b"\x1b[201~", false, Parsed::Event(InputEvent::PasteEnd, 6)),
+            // Mouse
+            (b"\x1b[M !!", false, mouse_down(6)),
+            (b"\x1b[M !", false, Parsed::Incomplete),
+            (b"\x1b[<0;1;1M", false, mouse_down(9)),
+            (b"\x1b[<0;1M", false, Parsed::Skip(7)),
+        ];
+        for &(buf, force, ref expected) in table {
+            assert_eq!(parse(buf, force), *expected, "{:?}", buf);
+        }
+    }
+
+    fn mouse_down(len: usize) -> Parsed {
+        let ev = MouseEvent {
+            action: MouseAction::Down(MouseButton::Left),
+            column: 0,
+            row: 0,
+            modifiers: Modifiers::default(),
+        };
+        Parsed::Event(InputEvent::Mouse(ev), len)
+    }
+
+    #[test]
+    fn overlong_sequence() {
+        let mut buf = b"\x1b[".to_vec();
+        buf.extend(vec![b'1'; MAX_SEQUENCE - 3]);
+        assert_eq!(parse(&buf, false), Parsed::Incomplete);
+        buf.push(b'1');
+        assert_eq!(parse(&buf, false), Parsed::Skip(MAX_SEQUENCE));
+    }
+}
@@ -0,0 +1,106 @@
+//! Detection of what is actually behind fd 0, 1 and 2
+
+/// What kind of object a standard stream refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StdioKind {
+    /// Terminal (`isatty` returns true)
+    Tty,
+    /// Pipe or FIFO
+    Pipe,
+    /// Regular file, e.g. `tool < file`
+    File,
+    /// Socket, e.g. when started from inetd or systemd socket activation
+    Socket,
+    /// Character device which is not a terminal, e.g. `/dev/null`
+    CharDevice,
+    /// Block device
+    BlockDevice,
+    /// The file descriptor is not open
+    Closed,
+    /// Something else, or detection is not supported on this platform
+    Unknown,
+}
+
+impl StdioKind {
+    /// Query the kind of object behind a raw file descriptor
+    #[cfg(unix)]
+    pub fn of_fd(fd: ::std::os::unix::io::RawFd) -> StdioKind {
+        unsafe {
+            let mut st: ::libc::stat = ::std::mem::zeroed();
+            if ::libc::fstat(fd, &mut st) != 0 {
+                if ::std::io::Error::last_os_error().raw_os_error() == Some(::libc::EBADF) {
+                    return StdioKind::Closed;
+                }
+                return StdioKind::Unknown;
+            }
+            match st.st_mode & ::libc::S_IFMT {
+                ::libc::S_IFREG => StdioKind::File,
+                ::libc::S_IFIFO => StdioKind::Pipe,
+                ::libc::S_IFSOCK => StdioKind::Socket,
+                ::libc::S_IFBLK => StdioKind::BlockDevice,
+                ::libc::S_IFCHR => {
+                    if ::libc::isatty(fd) == 1 {
+                        StdioKind::Tty
+                    } else {
+                        StdioKind::CharDevice
+                    }
+                }
+                _ => StdioKind::Unknown,
+            }
+        }
+    }
+
+    /// Is it an interactive terminal
+    pub fn is_tty(self) -> bool {
+        self == StdioKind::Tty
+    }
+
+    /// Can the file offset be moved with `lseek`
+    pub fn is_seekable(self) -> bool {
+        matches!(self, StdioKind::File | StdioKind::BlockDevice)
+    }
+
+    /// Can the content be memory-mapped
+    pub fn is_mappable(self) -> bool {
+        self == StdioKind::File
+    }
+
+    /// Size of chunks the worker thread should read or write at once
+    pub(crate) fn chunk_size(self) -> usize {
+        if self.is_seekable() {
+            ::BIGBUFSIZ
+        } else {
+            ::BUFSIZ
+        }
+    }
+
+    /// Should the worker thread flush after every chunk, not only before exiting
+    pub(crate) fn flush_each_chunk(self) -> bool {
+        !self.is_seekable()
+    }
+}
+
+#[cfg(unix)]
+fn detect(fd: ::std::os::unix::io::RawFd) -> StdioKind {
+    StdioKind::of_fd(fd)
+}
+
+#[cfg(not(unix))]
+fn detect(_fd: i32) -> StdioKind {
+    StdioKind::Unknown
+}
+
+/// What kind of object is stdin
+pub fn stdin_kind() -> StdioKind {
+    detect(0)
+}
+
+/// What kind of object is stdout
+pub fn stdout_kind() -> StdioKind {
+    detect(1)
+}
+
+/// What kind of object is stderr
+pub fn stderr_kind() -> StdioKind {
+    detect(2)
+}
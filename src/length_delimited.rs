@@ -0,0 +1,308 @@
+//! Length-prefixed binary frames over stdin and stdout, e.g. for piping protobuf messages
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use std::io::{Error, ErrorKind};
+use tokio_io::AsyncWrite;
+use {stdin, stdout, ThreadedStdin, ThreadedStdout};
+
+/// How length of each frame is encoded before its content
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthPrefix {
+    /// 2 bytes, big endian
+    U16Be,
+    /// 2 bytes, little endian
+    U16Le,
+    /// 4 bytes, big endian
+    U32Be,
+    /// 4 bytes, little endian
+    U32Le,
+    /// 8 bytes, big endian
+    U64Be,
+    /// 8 bytes, little endian
+    U64Le,
+    /// Protobuf-style base 128 varint, 1 to 10 bytes
+    Varint,
+}
+
+impl LengthPrefix {
+    fn max_len(self) -> u64 {
+        match self {
+            LengthPrefix::U16Be | LengthPrefix::U16Le => u64::from(u16::MAX),
+            LengthPrefix::U32Be | LengthPrefix::U32Le => u64::from(u32::MAX),
+            _ => u64::MAX,
+        }
+    }
+
+    fn encode(self, len: u64, dst: &mut BytesMut) {
+        dst.reserve(10);
+        match self {
+            LengthPrefix::U16Be => dst.put_u16_be(len as u16),
+            LengthPrefix::U16Le => dst.put_u16_le(len as u16),
+            LengthPrefix::U32Be => dst.put_u32_be(len as u32),
+            LengthPrefix::U32Le => dst.put_u32_le(len as u32),
+            LengthPrefix::U64Be => dst.put_u64_be(len),
+            LengthPrefix::U64Le => dst.put_u64_le(len),
+            LengthPrefix::Varint => {
+                let mut x = len;
+                while x >= 0x80 {
+                    dst.put_u8((x as u8) | 0x80);
+                    x >>= 7;
+                }
+                dst.put_u8(x as u8);
+            }
+        }
+    }
+
+    /// Returns frame length and size of the prefix itself, or `None` if more data is needed
+    fn decode(self, src: &[u8]) -> Result<Option<(u64, usize)>, Error> {
+        let fixed = |n: usize, be: bool| {
+            if src.len() < n {
+                return None;
+            }
+            let mut x = 0u64;
+            for i in 0..n {
+                let b = if be { src[i] } else { src[n - 1 - i] };
+                x = (x << 8) | u64::from(b);
+            }
+            Some((x, n))
+        };
+        Ok(match self {
+            LengthPrefix::U16Be => fixed(2, true),
+            LengthPrefix::U16Le => fixed(2, false),
+            LengthPrefix::U32Be => fixed(4, true),
+            LengthPrefix::U32Le => fixed(4, false),
+            LengthPrefix::U64Be => fixed(8, true),
+            LengthPrefix::U64Le => fixed(8, false),
+            LengthPrefix::Varint => {
+                let mut x = 0u64;
+                for (i, &b) in src.iter().enumerate() {
+                    if i >= 10 || (i == 9 && b > 1) {
+                        return Err(Error::new(ErrorKind::InvalidData, "malformed varint"));
+                    }
+                    x |= u64::from(b & 0x7F) << (7 * i);
+                    if b & 0x80 == 0 {
+                        return Ok(Some((x, i + 1)));
+                    }
+                }
+                None
+            }
+        })
+    }
+}
+
+fn too_big() -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        "frame is larger than the maximum frame size",
+    )
+}
+
+/// Stream of frames read from stdin, see `stdin_length_delimited`.
+///
+/// After an error the position within the input is lost, so the stream should not be used further.
+pub struct LengthDelimitedStdin {
+    inner: ThreadedStdin,
+    prefix: LengthPrefix,
+    max_frame_size: usize,
+    buf: BytesMut,
+}
+
+impl LengthDelimitedStdin {
+    /// Get the underlying `ThreadedStdin` back. Data buffered, but not yet returned as frames,
+    /// is pushed back into it.
+    pub fn into_inner(mut self) -> ThreadedStdin {
+        self.inner.unread(&self.buf);
+        self.inner
+    }
+}
+
+impl Stream for LengthDelimitedStdin {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        loop {
+            if let Some((len, hl)) = self.prefix.decode(&self.buf)? {
+                if len > self.max_frame_size as u64 {
+                    return Err(too_big());
+                }
+                let len = len as usize;
+                if self.buf.len() >= hl + len {
+                    self.buf.advance(hl);
+                    return Ok(Async::Ready(Some(self.buf.split_to(len).freeze())));
+                }
+            }
+            match try_ready!(self.inner.poll()) {
+                Some(chunk) => self.buf.extend_from_slice(&chunk),
+                None if self.buf.is_empty() => return Ok(Async::Ready(None)),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "partial frame at the end of input",
+                    ))
+                }
+            }
+        }
+    }
+}
+
+/// Sink of frames written to stdout, see `stdout_length_delimited`.
+///
+/// Each frame, together with its length prefix, is sent to the worker thread as one chunk.
+pub struct LengthDelimitedStdout {
+    inner: ThreadedStdout,
+    prefix: LengthPrefix,
+    max_frame_size: usize,
+}
+
+impl LengthDelimitedStdout {
+    /// Get the underlying `ThreadedStdout` back
+    pub fn into_inner(self) -> ThreadedStdout {
+        self.inner
+    }
+}
+
+impl Sink for LengthDelimitedStdout {
+    type SinkItem = Bytes;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: Bytes) -> StartSend<Bytes, Error> {
+        let len = item.len() as u64;
+        if len > self.max_frame_size as u64 || len > self.prefix.max_len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "frame is larger than the maximum frame size",
+            ));
+        }
+        let mut frame = BytesMut::with_capacity(item.len() + 10);
+        self.prefix.encode(len, &mut frame);
+        frame.extend_from_slice(&item);
+        match self.inner.start_send_chunk(frame.freeze())? {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(_) => Ok(AsyncSink::NotReady(item)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        self.inner.poll_complete_chunks()
+    }
+
+    fn close(&mut self) -> Poll<(), Error> {
+        try_ready!(self.poll_complete());
+        self.inner.shutdown()
+    }
+}
+
+impl ThreadedStdin {
+    /// Turn into a stream of frames, each prefixed by its length encoded according to `prefix`.
+    ///
+    /// Frames larger than `max_frame_size` and a truncated frame at the end of input are errors.
+    pub fn length_delimited(
+        self,
+        prefix: LengthPrefix,
+        max_frame_size: usize,
+    ) -> LengthDelimitedStdin {
+        LengthDelimitedStdin {
+            inner: self,
+            prefix,
+            max_frame_size,
+            buf: BytesMut::new(),
+        }
+    }
+}
+
+impl ThreadedStdout {
+    /// Turn into a sink of frames, each prefixed by its length encoded according to `prefix`.
+    ///
+    /// Sending a frame larger than `max_frame_size` (or not representable by `prefix`) is an error.
+    pub fn length_delimited(
+        self,
+        prefix: LengthPrefix,
+        max_frame_size: usize,
+    ) -> LengthDelimitedStdout {
+        LengthDelimitedStdout {
+            inner: self,
+            prefix,
+            max_frame_size,
+        }
+    }
+}
+
+/// Constructor for a stream of length-prefixed frames from stdin
+pub fn stdin_length_delimited(
+    queue_size: usize,
+    prefix: LengthPrefix,
+    max_frame_size: usize,
+) -> LengthDelimitedStdin {
+    stdin(queue_size).length_delimited(prefix, max_frame_size)
+}
+
+/// Constructor for a sink of length-prefixed frames to stdout
+pub fn stdout_length_delimited(
+    queue_size: usize,
+    prefix: LengthPrefix,
+    max_frame_size: usize,
+) -> LengthDelimitedStdout {
+    stdout(queue_size).length_delimited(prefix, max_frame_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Input and the expected frame length and prefix size
+    type Case = (&'static [u8], Option<(u64, usize)>);
+
+    #[test]
+    fn varint_decode() {
+        let v = LengthPrefix::Varint;
+        let table: &[Case] = &[
+            (b"", None),
+            (b"\x00", Some((0, 1))),
+            (b"\x7f", Some((127, 1))),
+            (b"\x80\x01", Some((128, 2))),
+            (b"\xac\x02rest", Some((300, 2))),
+            (b"\x80", None),
+            (b"\xff\xff\xff", None),
+            (
+                b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01",
+                Some((u64::MAX, 10)),
+            ),
+        ];
+        for &(src, expected) in table {
+            assert_eq!(v.decode(src).unwrap(), expected, "{:?}", src);
+        }
+        // More than 64 bits
+        assert!(v
+            .decode(b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x02")
+            .is_err());
+        assert!(v
+            .decode(b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x81\x00")
+            .is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let prefixes = [
+            LengthPrefix::U16Be,
+            LengthPrefix::U16Le,
+            LengthPrefix::U32Be,
+            LengthPrefix::U32Le,
+            LengthPrefix::U64Be,
+            LengthPrefix::U64Le,
+            LengthPrefix::Varint,
+        ];
+        for &p in &prefixes {
+            for &len in &[0, 1, 127, 128, 300, 65535, p.max_len()] {
+                let mut dst = BytesMut::new();
+                p.encode(len, &mut dst);
+                assert_eq!(p.decode(&dst).unwrap(), Some((len, dst.len())), "{:?}", p);
+                assert_eq!(p.decode(&dst[..dst.len() - 1]).unwrap(), None, "{:?}", p);
+            }
+        }
+        let mut dst = BytesMut::new();
+        LengthPrefix::U16Le.encode(0x0102, &mut dst);
+        assert_eq!(&dst[..], b"\x02\x01");
+    }
+}
@@ -20,6 +20,15 @@
 //!
 //! It works by starting separate threads, which do actual synchronous I/O and communicating to
 //! the asynchronous world using [future::sync::mpsc](http://alexcrichton.com/futures-rs/futures/sync/mpsc/index.html).
+//! When stdin is a regular file, `stdin_mmap` can avoid the thread and serve data from a memory mapping instead.
+//!
+//! Optional cargo features:
+//!
+//! * `ndjson` - newline-delimited JSON stream and sink, see the `ndjson` module.
+//! * `csv` - CSV/TSV record stream and sink, see the `csv_stdio` module.
+//! * `encoding` - decoding stdin from and encoding stdout to legacy encodings, see the `transcode` module.
+//! * `gzip`, `zstd`, `xz`, `bzip2` - compression formats for `ThreadedStdin::auto_decompress`
+//!   and `ThreadedStdout::compress`.
 //!
 //! For Unix (Linux, OS X) better use [tokio-file-unix](https://crates.io/crates/tokio-file-unix).
 //!
@@ -27,32 +36,559 @@
 //!
 //! * stdin/stdout are not expected to be ever normally used after using functions from this crate
 //! * Allocation-heavy.
-//! * All errors collapsed to ErrorKind::Other (for stdout) or ErrorKind::BrokenPipe (for stdin)
 //! * Failure to write to stdout is only seen after attempting to send there about 3 more buffers.
 
+extern crate bytes;
+#[cfg(feature = "bzip2")]
+extern crate bzip2;
+#[cfg(feature = "csv")]
+extern crate csv;
+#[cfg(feature = "csv")]
+extern crate csv_core;
+#[cfg(feature = "encoding")]
+extern crate encoding_rs;
+#[cfg(feature = "gzip")]
+extern crate flate2;
+#[macro_use]
 extern crate futures;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(unix)]
+extern crate memmap2;
+#[cfg(any(feature = "ndjson", feature = "csv"))]
+extern crate serde;
+#[cfg(feature = "ndjson")]
+extern crate serde_json;
+#[cfg(test)]
+extern crate tokio;
 extern crate tokio_io;
+extern crate tokio_timer;
+#[cfg(feature = "xz")]
+extern crate xz2;
+#[cfg(feature = "zstd")]
+extern crate zstd;
 
 const BUFSIZ: usize = 8192;
+const BIGBUFSIZ: usize = 65536;
 
-use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+mod keys;
+pub use keys::{InputEvent, KeyCode, KeyEvents, KeyPress, Modifiers};
+mod kind;
+pub use kind::{stderr_kind, stdin_kind, stdout_kind, StdioKind};
+#[cfg(unix)]
+mod mmap;
+mod password;
+pub use password::{Password, ReadPassword};
+mod prompt;
+pub use prompt::{Prompt, PromptResult, ReadLine};
+mod ratelimit;
+mod seek;
+pub use seek::SeekFuture;
+mod split;
+pub use split::Records;
+mod compression;
+pub use compression::{AutoDecompress, Compression};
+mod length_delimited;
+mod metered;
+pub use metered::{Meter, MeterDisplay, MeterSnapshot, Metered};
+mod mouse;
+pub use length_delimited::{
+    stdin_length_delimited, stdout_length_delimited, LengthDelimitedStdin, LengthDelimitedStdout,
+    LengthPrefix,
+};
+pub use mouse::{MouseAction, MouseButton, MouseCapture, MouseEvent};
+mod stats;
+pub use stats::Stats;
+mod status;
+pub use status::StatusLine;
+#[cfg(unix)]
+mod term;
+#[cfg(unix)]
+pub use term::RawModeGuard;
+mod timeout;
+#[cfg(unix)]
+mod tty;
+pub use timeout::Idle;
+#[cfg(unix)]
+pub use tty::{tty, ThreadedTty};
+mod utf8;
+pub use utf8::{Chars, InvalidUtf8, Utf8Chunks};
+#[cfg(unix)]
+mod winsize;
+#[cfg(unix)]
+pub use winsize::{resize_events, terminal_size, ResizeEvents};
+#[cfg(feature = "csv")]
+pub mod csv_stdio;
+#[cfg(feature = "ndjson")]
+pub mod ndjson;
+#[cfg(test)]
+mod testutil;
+#[cfg(feature = "encoding")]
+pub mod transcode;
+
+use bytes::Bytes;
+use futures::{Async, AsyncSink, Poll, Sink, Stream};
 use std::cell::RefCell;
-use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::io::{BufRead, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
 use std::rc::Rc;
 use std::sync::{Arc, LockResult, Mutex, MutexGuard, PoisonError, TryLockError, TryLockResult};
 use std::thread::JoinHandle;
+use std::time::Duration;
 use tokio_io::{AsyncRead, AsyncWrite};
 
-type BBR = futures::sync::mpsc::Receiver<Box<[u8]>>;
-type BBS = futures::sync::mpsc::Sender<Box<[u8]>>;
+/// What the async side sends to the writer thread
+enum Outgoing {
+    /// Chunk of data. Empty chunk tells the thread to finish and exit.
+    Data(Bytes),
+    /// Flush the output, including a sync flush of the compressor
+    Flush,
+    /// Compress everything after this
+    Compress(Compression, u32),
+}
+type OutgoingR = futures::sync::mpsc::Receiver<Outgoing>;
+type OutgoingS = futures::sync::mpsc::Sender<Outgoing>;
+
+/// What the reader thread sends to the async side
+enum Incoming {
+    /// Chunk of data or, after `Control::Split`, a record
+    Data(Bytes),
+    /// Reply to `Control::Seek`. Everything before it should be discarded.
+    Seeked(Result<u64>),
+    /// Reply to `Control::Split`. Everything after it is records.
+    Switched,
+    /// End of file, after `Control::Split` (when empty `Data` is a valid record)
+    Eof,
+    Error(Error),
+}
+type IncomingR = futures::sync::mpsc::Receiver<Incoming>;
+type IncomingS = futures::sync::mpsc::Sender<Incoming>;
+
+/// Requests from the async side to the reader thread
+enum Control {
+    /// Seek to `to`. If that fails, seek to `restore` (logical position of the async side)
+    /// to compensate for discarded chunks, and report the error.
+    Seek { to: SeekFrom, restore: u64 },
+    /// Start sending records instead of raw chunks. Thread replies with `Incoming::Switched`
+    /// and waits for `Control::Resume`.
+    Split,
+    /// Continue splitting with this splitter, which has already seen data sent before `Switched`
+    Resume(split::Splitter),
+    /// Start decompressing. Thread replies with `Incoming::Switched` and waits for `Control::Prefix`.
+    Decompress(Compression),
+    /// Compressed data sent before `Switched`, to be decompressed first
+    Prefix(Bytes),
+}
+/// Async side of the reader thread
+struct Worker {
+    rcv: IncomingR,
+    ctl: std::sync::mpsc::Sender<Control>,
+    seekable: bool,
+    /// Logical position of the next byte to be returned (meaningful only for seekable readers)
+    pos: u64,
+    /// `Control::Seek` is sent, but `Incoming::Seeked` is not yet received
+    seeking: bool,
+    activity: timeout::Activity,
+    stats: Arc<stats::Counters>,
+    /// Taken when the thread is joined
+    jh: Option<JoinHandle<()>>,
+    /// Panic of the thread was already reported as an error
+    panic_reported: bool,
+}
+
+impl Worker {
+    /// Next message from the reader thread
+    fn recv(&mut self) -> Poll<Option<Incoming>, ()> {
+        let ret = self.rcv.poll();
+        match ret {
+            Ok(Async::Ready(Some(Incoming::Data(ref x)))) => self.stats.dequeue(x.len()),
+            Ok(Async::Ready(None)) if !self.panic_reported => {
+                // The channel is closed while the thread is exiting, possibly still unwinding
+                if let Some(jh) = self.jh.take() {
+                    let _ = jh.join();
+                }
+                // If it panicked, report that once
+                if let Some(e) = self.stats.panic_error("stdin") {
+                    self.panic_reported = true;
+                    return Ok(Async::Ready(Some(Incoming::Error(e))));
+                }
+            }
+            _ => (),
+        }
+        ret
+    }
+
+    /// Get the next chunk, skipping stale chunks if a seek is in progress
+    fn poll_chunk(&mut self) -> Poll<Bytes, Error> {
+        loop {
+            match self.recv() {
+                Ok(Async::Ready(Some(Incoming::Data(x)))) => {
+                    if !self.seeking {
+                        return Ok(Async::Ready(x));
+                    }
+                }
+                Ok(Async::Ready(Some(Incoming::Seeked(r)))) => {
+                    self.seeking = false;
+                    if let Ok(p) = r {
+                        self.pos = p;
+                    }
+                }
+                Ok(Async::Ready(Some(Incoming::Eof))) => return Ok(Async::Ready(Bytes::new())),
+                Ok(Async::Ready(Some(Incoming::Switched))) => (),
+                Ok(Async::Ready(Some(Incoming::Error(e)))) => return Err(e),
+                Ok(Async::Ready(None)) => return Err(ErrorKind::BrokenPipe.into()),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(_) => return Err(ErrorKind::Other.into()),
+            }
+        }
+    }
+
+    fn poll_seek(&mut self, pos: SeekFrom) -> Poll<u64, Error> {
+        if !self.seeking {
+            if !self.seekable {
+                return Err(seek::not_seekable());
+            }
+            let to = match pos {
+                SeekFrom::Current(d) => {
+                    let p = if d >= 0 {
+                        self.pos.checked_add(d as u64)
+                    } else {
+                        self.pos.checked_sub(d.unsigned_abs())
+                    };
+                    match p {
+                        Some(p) => SeekFrom::Start(p),
+                        None => return Err(ErrorKind::InvalidInput.into()),
+                    }
+                }
+                x => x,
+            };
+            let cmd = Control::Seek {
+                to,
+                restore: self.pos,
+            };
+            if self.ctl.send(cmd).is_err() {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            self.seeking = true;
+        }
+        loop {
+            match self.recv() {
+                Ok(Async::Ready(Some(Incoming::Data(_)))) => (),
+                Ok(Async::Ready(Some(Incoming::Switched))) => (),
+                Ok(Async::Ready(Some(Incoming::Eof))) => (),
+                Ok(Async::Ready(Some(Incoming::Error(_)))) => (),
+                Ok(Async::Ready(Some(Incoming::Seeked(r)))) => {
+                    self.seeking = false;
+                    let p = r?;
+                    self.pos = p;
+                    return Ok(Async::Ready(p));
+                }
+                Ok(Async::Ready(None)) => return Err(ErrorKind::BrokenPipe.into()),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(_) => return Err(ErrorKind::Other.into()),
+            }
+        }
+    }
+}
+
+fn threaded_reader_impl<F, R>(
+    queue_size: usize,
+    chunk_size: usize,
+    start_pos: Option<u64>,
+    open: F,
+) -> Worker
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: seek::MaybeSeek + 'static,
+{
+    let (snd_, rcv): (IncomingS, IncomingR) = futures::sync::mpsc::channel(queue_size);
+    let (ctl, ctl_rcv) = std::sync::mpsc::channel();
+    let activity = timeout::Activity::new();
+    let activity_ = activity.clone();
+    let stats = stats::Counters::new();
+    let stats_ = stats.clone();
+    let jh = std::thread::spawn(move || {
+        stats_.clone().run_worker(|| {
+            use seek::MaybeSeek;
+            let mut snd = snd_.wait();
+            let send_counted = |snd: &mut futures::sink::Wait<IncomingS>, x: Incoming| {
+                if let Incoming::Data(ref b) = x {
+                    stats_.enqueue(b.len());
+                }
+                stats_.queue_wait(|| snd.send(x))
+            };
+            let mut r = compression::Source::Plain(open());
+            let mut buf = vec![0; chunk_size];
+            let mut splitter: Option<split::Splitter> = None;
+            loop {
+                while let Ok(cmd) = ctl_rcv.try_recv() {
+                    match cmd {
+                        Control::Seek { to, restore } => {
+                            let ret = r.seek_to(to);
+                            if ret.is_err() {
+                                let _ = r.seek_to(SeekFrom::Start(restore));
+                            }
+                            if let Some(ref mut sp) = splitter {
+                                sp.clear();
+                            }
+                            if snd.send(Incoming::Seeked(ret)).is_err() {
+                                return;
+                            }
+                        }
+                        Control::Split => {
+                            if snd.send(Incoming::Switched).is_err() {
+                                return;
+                            }
+                            match ctl_rcv.recv() {
+                                Ok(Control::Resume(sp)) => splitter = Some(sp),
+                                _ => return,
+                            }
+                        }
+                        Control::Decompress(format) => {
+                            if snd.send(Incoming::Switched).is_err() {
+                                return;
+                            }
+                            let prefix = match ctl_rcv.recv() {
+                                Ok(Control::Prefix(x)) => x,
+                                _ => return,
+                            };
+                            r = match r.decompress(format, prefix) {
+                                Ok(x) => x,
+                                Err(e) => {
+                                    let _ = snd.send(Incoming::Error(e));
+                                    return;
+                                }
+                            };
+                        }
+                        Control::Resume(_) | Control::Prefix(_) => (),
+                    }
+                }
+                let ret = match stats::counted_read(&stats_, &mut r, &mut buf[..]) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        let _ = snd.send(Incoming::Error(e));
+                        break;
+                    }
+                };
+                if ret > 0 {
+                    activity_.touch();
+                }
+                let sent = match splitter {
+                    None => send_counted(&mut snd, Incoming::Data(Bytes::from(&buf[0..ret]))),
+                    Some(ref mut sp) => sp.feed(&buf[0..ret], |x| send_counted(&mut snd, x)),
+                };
+                if sent.is_err() {
+                    break;
+                }
+            }
+        })
+    });
+    Worker {
+        rcv,
+        ctl,
+        seekable: start_pos.is_some(),
+        pos: start_pos.unwrap_or(0),
+        seeking: false,
+        activity,
+        stats,
+        jh: Some(jh),
+        panic_reported: false,
+    }
+}
+
+enum StdinSource {
+    Threaded(Worker),
+    #[cfg(unix)]
+    Mapped(mmap::MappedStdin),
+}
 
 /// Asynchronous stdin
+///
+/// Besides `AsyncRead`, it is also a `Stream` of chunks, as they were delivered by the worker thread.
 pub struct ThreadedStdin {
-    debt: Option<Box<[u8]>>,
-    rcv: BBR,
+    /// Data received, but not yet consumed (or pushed back with `unread`)
+    debt: Option<Bytes>,
+    /// End of file was seen by `peek`, but not yet reported
+    pending_eof: bool,
+    src: StdinSource,
+    kind: StdioKind,
+    activity: timeout::Activity,
+    timeout: timeout::ReadTimeout,
 }
 
 impl ThreadedStdin {
+    fn from_source(
+        src: StdinSource,
+        kind: StdioKind,
+        activity: timeout::Activity,
+    ) -> ThreadedStdin {
+        ThreadedStdin {
+            debt: None,
+            pending_eof: false,
+            src,
+            kind,
+            activity,
+            timeout: Default::default(),
+        }
+    }
+    fn from_worker(w: Worker, kind: StdioKind) -> ThreadedStdin {
+        let activity = w.activity.clone();
+        ThreadedStdin::from_source(StdinSource::Threaded(w), kind, activity)
+    }
+    /// Get next chunk from the worker thread or the mapping. Empty chunk means end of file.
+    fn poll_source(&mut self, max: usize) -> Poll<Bytes, Error> {
+        if self.pending_eof {
+            self.pending_eof = false;
+            return Ok(Async::Ready(Bytes::new()));
+        }
+        let ret = match self.src {
+            StdinSource::Threaded(ref mut w) => w.poll_chunk()?,
+            #[cfg(unix)]
+            StdinSource::Mapped(ref mut m) => {
+                self.activity.touch();
+                Async::Ready(m.chunk(max))
+            }
+        };
+        if ret.is_ready() {
+            self.timeout.reset();
+        } else {
+            self.timeout.check()?;
+        }
+        Ok(ret)
+    }
+    /// Account for `n` bytes handed out to user
+    fn advance(&mut self, n: usize) {
+        if let StdinSource::Threaded(ref mut w) = self.src {
+            w.pos = w.pos.wrapping_add(n as u64);
+        }
+    }
+    /// Whether the worker thread is still running. It exits on a read error or if it panics,
+    /// but not at end of file. Always true for memory-mapped stdin.
+    pub fn is_alive(&self) -> bool {
+        match self.src {
+            StdinSource::Threaded(ref w) => w.stats.is_alive(),
+            #[cfg(unix)]
+            StdinSource::Mapped(_) => true,
+        }
+    }
+    /// What kind of object stdin was when this handle was created
+    pub fn kind(&self) -> StdioKind {
+        self.kind
+    }
+    /// Whether this handle reads from a memory mapping (see `stdin_mmap`) instead of a thread
+    pub fn is_mmapped(&self) -> bool {
+        match self.src {
+            StdinSource::Threaded(_) => false,
+            #[cfg(unix)]
+            StdinSource::Mapped(_) => true,
+        }
+    }
+    /// Whether `seek` is supported. It is when stdin is a regular file or a block device,
+    /// or for `threaded_seekable_reader`.
+    pub fn is_seekable(&self) -> bool {
+        match self.src {
+            StdinSource::Threaded(ref w) => w.seekable,
+            #[cfg(unix)]
+            StdinSource::Mapped(_) => true,
+        }
+    }
+    /// Move the read position, like `std::io::Seek::seek`.
+    ///
+    /// Chunks already read ahead by the worker thread are discarded.
+    /// Should be called repeatedly (with the same `pos`) until it returns `Async::Ready`,
+    /// without reading in between.
+    /// Fails with `ErrorKind::Unsupported` if stdin is not seekable.
+    pub fn poll_seek(&mut self, pos: SeekFrom) -> Poll<u64, Error> {
+        let ret = match self.src {
+            StdinSource::Threaded(ref mut w) => w.poll_seek(pos),
+            #[cfg(unix)]
+            StdinSource::Mapped(ref mut m) => {
+                // Mapping position is already past the data we are holding
+                let debt = self.debt.as_ref().map_or(0, |x| x.len() as i64);
+                let pos = match pos {
+                    SeekFrom::Current(d) => SeekFrom::Current(d - debt),
+                    x => x,
+                };
+                m.seek(pos).map(Async::Ready)
+            }
+        };
+        if let Ok(Async::Ready(_)) = ret {
+            self.debt = None;
+            self.pending_eof = false;
+        }
+        ret
+    }
+    /// Copy the beginning of the incoming data to `buf` without consuming it.
+    ///
+    /// Waits (returns `WouldBlock`, like `read`) until `buf` can be filled completely,
+    /// so it may span multiple chunks. Returns less than `buf.len()` only on end of file.
+    pub fn peek(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let have = self.debt.as_ref().map_or(0, |x| x.len());
+            if have >= buf.len() || self.pending_eof {
+                break;
+            }
+            match self.poll_source(buf.len() - have)? {
+                Async::NotReady => return Err(ErrorKind::WouldBlock.into()),
+                Async::Ready(ref x) if x.is_empty() => self.pending_eof = true,
+                Async::Ready(x) => {
+                    self.debt = Some(match self.debt.take() {
+                        None => x,
+                        Some(debt) => concat(&debt, &x),
+                    })
+                }
+            }
+        }
+        let debt = match self.debt {
+            Some(ref x) => &x[..],
+            None => &[],
+        };
+        let n = ::std::cmp::min(buf.len(), debt.len());
+        buf[0..n].copy_from_slice(&debt[0..n]);
+        Ok(n)
+    }
+    /// Push `data` back to the front, so it will be returned by the following reads
+    /// before anything else.
+    ///
+    /// For the purpose of `SeekFrom::Current`, the pushed back bytes are treated as un-consumed.
+    pub fn unread(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.debt = Some(match self.debt.take() {
+            None => Bytes::from(data),
+            Some(debt) => concat(data, &debt),
+        });
+        if let StdinSource::Threaded(ref mut w) = self.src {
+            w.pos = w.pos.wrapping_sub(data.len() as u64);
+        }
+    }
+    /// Future-returning version of `poll_seek`. Resolves to the new position counted from the start.
+    pub fn seek(self, pos: SeekFrom) -> SeekFuture {
+        SeekFuture::new(self, pos)
+    }
+    /// Make reads fail with `ErrorKind::TimedOut` if no data arrives within `timeout`.
+    ///
+    /// Data arriving later is not lost: it is returned by subsequent reads.
+    /// Requires Tokio timer to be available (it is in Tokio runtime).
+    pub fn with_timeout(mut self, timeout: Duration) -> ThreadedStdin {
+        self.set_read_timeout(Some(timeout));
+        self
+    }
+    /// Set or remove the read timeout, see `with_timeout`
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout::ReadTimeout::new(timeout);
+    }
+    /// Current read timeout
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.timeout.timeout
+    }
+    /// Future which resolves when no input has been arriving for `duration`.
+    ///
+    /// It does not borrow the `ThreadedStdin`, so can be used concurrently with reading,
+    /// e.g. `select`ed with it. Requires Tokio timer to be available.
+    pub fn idle(&self, duration: Duration) -> Idle {
+        Idle::new(self.activity.clone(), duration)
+    }
     /// Wrap into `Arc<Mutex>` to make it clonable and sendable
     pub fn make_sendable(self) -> SendableStdin {
         SendableStdin::new(self)
@@ -65,61 +601,179 @@ impl ThreadedStdin {
 
 /// Constructor for the `ThreadedStdin`
 pub fn stdin(queue_size: usize) -> ThreadedStdin {
-    let (snd_, rcv): (BBS, BBR) = futures::sync::mpsc::channel(queue_size);
-    std::thread::spawn(move || {
-        let mut snd = snd_;
-        let sin = ::std::io::stdin();
-        let mut sin_lock = sin.lock();
-        let mut buf = vec![0; BUFSIZ];
-        while let Ok(ret) = sin_lock.read(&mut buf[..]) {
-            let content = buf[0..ret].to_vec().into_boxed_slice();
-            snd = match snd.send(content).wait() {
-                Ok(x) => x,
-                Err(_) => break,
+    let kind = stdin_kind();
+    #[cfg(unix)]
+    {
+        if kind.is_seekable() {
+            if let Ok(start) = seek::Fd0::new().stream_position() {
+                let w = threaded_reader_impl(queue_size, kind.chunk_size(), Some(start), || {
+                    seek::Seekable(seek::Fd0::new())
+                });
+                return ThreadedStdin::from_worker(w, kind);
             }
         }
+    }
+    let w = threaded_reader_impl(queue_size, kind.chunk_size(), None, || {
+        seek::NotSeekable(::std::io::stdin().lock())
     });
-    ThreadedStdin { debt: None, rcv }
+    ThreadedStdin::from_worker(w, kind)
+}
+
+/// Read from arbitrary blocking reader using a thread, the same way as `stdin` does.
+pub fn threaded_reader<R: Read + Send + 'static>(r: R, queue_size: usize) -> ThreadedStdin {
+    let w = threaded_reader_impl(queue_size, BUFSIZ, None, move || seek::NotSeekable(r));
+    ThreadedStdin::from_worker(w, StdioKind::Unknown)
+}
+
+/// Like `threaded_reader`, but the result supports `ThreadedStdin::seek`
+/// (unless querying the initial position fails).
+pub fn threaded_seekable_reader<R: Read + Seek + Send + 'static>(
+    mut r: R,
+    queue_size: usize,
+) -> ThreadedStdin {
+    let start = r.stream_position().ok();
+    let w = threaded_reader_impl(queue_size, BIGBUFSIZ, start, move || seek::Seekable(r));
+    ThreadedStdin::from_worker(w, StdioKind::Unknown)
+}
+
+/// Constructor for the `ThreadedStdin` which avoids the thread when stdin is a regular file.
+///
+/// If fd 0 is a regular file (`tool < bigfile`), the rest of it, starting from the current
+/// file offset, is memory-mapped and served from the mapping, without a worker thread
+/// and channel. `Read` copies straight from the mapping into the caller's buffer;
+/// chunks returned by the `Stream` impl are copied out of it.
+/// Otherwise (or if mapping fails, e.g. the file does not fit into the address space)
+/// it is the same as `stdin`.
+///
+/// Like with any `mmap`, truncating the file while it is being read leads to `SIGBUS`.
+/// The file offset of fd 0 is advanced to the consumed position when the handle is dropped.
+pub fn stdin_mmap(queue_size: usize) -> ThreadedStdin {
+    #[cfg(unix)]
+    {
+        let kind = stdin_kind();
+        if kind.is_mappable() {
+            if let Ok(Some(m)) = mmap::MappedStdin::open(queue_size) {
+                return ThreadedStdin::from_source(
+                    StdinSource::Mapped(m),
+                    kind,
+                    timeout::Activity::new(),
+                );
+            }
+        }
+    }
+    stdin(queue_size)
+}
+
+/// Data taken from the mapping, but not consumed, is left in stdin for the next reader
+#[cfg(unix)]
+impl Drop for ThreadedStdin {
+    fn drop(&mut self) {
+        if let StdinSource::Mapped(ref mut m) = self.src {
+            m.unconsume(self.debt.as_ref().map_or(0, |x| x.len()));
+        }
+    }
 }
 
 impl AsyncRead for ThreadedStdin {}
 impl Read for ThreadedStdin {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let mut handle_the_buffer = |incoming_buf: Box<[u8]>| {
-            let l = buf.len();
-            let dl = incoming_buf.len();
-            if l >= dl {
-                buf[0..dl].copy_from_slice(&incoming_buf);
-                (None, Ok(dl))
-            } else {
-                buf[0..l].copy_from_slice(&incoming_buf[0..l]);
-                let newdebt = Some(incoming_buf[l..].to_vec().into_boxed_slice());
-                (newdebt, Ok(l))
+        let incoming_buf = match self.debt.take() {
+            Some(debt) => debt,
+            None => {
+                #[cfg(unix)]
+                {
+                    if let StdinSource::Mapped(ref mut m) = self.src {
+                        if !self.pending_eof {
+                            self.activity.touch();
+                            return Ok(m.read(buf));
+                        }
+                    }
+                }
+                match self.poll_source(buf.len())? {
+                    Async::Ready(newbuf) => newbuf,
+                    Async::NotReady => return Err(ErrorKind::WouldBlock.into()),
+                }
             }
         };
-
-        let (new_debt, ret) = if let Some(debt) = self.debt.take() {
-            handle_the_buffer(debt)
+        let l = buf.len();
+        let dl = incoming_buf.len();
+        let n = if l >= dl {
+            buf[0..dl].copy_from_slice(&incoming_buf);
+            dl
         } else {
-            match self.rcv.poll() {
-                Ok(Async::Ready(Some(newbuf))) => handle_the_buffer(newbuf),
-                Ok(Async::Ready(None)) => (None, Err(ErrorKind::BrokenPipe.into())),
-                Ok(Async::NotReady) => (None, Err(ErrorKind::WouldBlock.into())),
-                Err(_) => (None, Err(ErrorKind::Other.into())),
+            buf[0..l].copy_from_slice(&incoming_buf[0..l]);
+            self.debt = Some(incoming_buf.slice_from(l));
+            l
+        };
+        self.advance(n);
+        Ok(n)
+    }
+}
+
+/// `fill_buf` returns `WouldBlock` when there is no data yet, like `read`.
+impl BufRead for ThreadedStdin {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        if self.debt.is_none() {
+            match self.poll_source(BIGBUFSIZ)? {
+                Async::Ready(ref x) if x.is_empty() => return Ok(&[]),
+                Async::Ready(x) => self.debt = Some(x),
+                Async::NotReady => return Err(ErrorKind::WouldBlock.into()),
             }
+        }
+        Ok(self.debt.as_ref().unwrap())
+    }
+    fn consume(&mut self, amt: usize) {
+        if let Some(debt) = self.debt.take() {
+            let amt = ::std::cmp::min(amt, debt.len());
+            if amt < debt.len() {
+                self.debt = Some(debt.slice_from(amt));
+            }
+            self.advance(amt);
+        }
+    }
+}
+
+impl Stream for ThreadedStdin {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        let chunk = match self.debt.take() {
+            Some(debt) => debt,
+            None => try_ready!(self.poll_source(BIGBUFSIZ)),
         };
-        self.debt = new_debt;
-        ret
+        self.advance(chunk.len());
+        if chunk.is_empty() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::Ready(Some(chunk)))
+        }
     }
 }
 
+fn concat(a: &[u8], b: &[u8]) -> Bytes {
+    let mut x = bytes::BytesMut::with_capacity(a.len() + b.len());
+    x.extend_from_slice(a);
+    x.extend_from_slice(b);
+    x.freeze()
+}
+
 /// Asynchronous stdout
 pub struct ThreadedStdout {
-    snd: BBS,
+    snd: OutgoingS,
     jh: Option<JoinHandle<()>>,
+    kind: StdioKind,
+    /// Something was written since the last flush
+    dirty: bool,
+    limit: Option<ratelimit::TokenBucket>,
+    stats: Arc<stats::Counters>,
 }
 
 impl ThreadedStdout {
+    /// What kind of object stdout (or stderr) was when this handle was created
+    pub fn kind(&self) -> StdioKind {
+        self.kind
+    }
     /// Wrap into `Arc<Mutex>` to make it clonable and sendable
     pub fn make_sendable(self) -> SendableStdout {
         SendableStdout::new(self)
@@ -128,57 +782,177 @@ impl ThreadedStdout {
     pub fn make_clonable(self) -> ClonableStdout {
         ClonableStdout::new(self)
     }
+
+    /// Whether the worker thread is still running. It exits after `shutdown`,
+    /// on a write error or if it panics.
+    pub fn is_alive(&self) -> bool {
+        self.stats.is_alive()
+    }
+
+    /// Error to report when the worker thread is gone
+    fn worker_error(&mut self) -> Error {
+        // The channel is closed while the thread is exiting, possibly still unwinding
+        if let Some(jh) = self.jh.take() {
+            let _ = jh.join();
+        }
+        self.stats
+            .exit_error("stdout")
+            .unwrap_or_else(|| ErrorKind::Other.into())
+    }
+
+    /// Wait until the writer thread has taken all queued chunks
+    pub(crate) fn poll_complete_chunks(&mut self) -> Poll<(), Error> {
+        match self.snd.poll_complete() {
+            Ok(x) => Ok(x),
+            Err(_) => Err(self.worker_error()),
+        }
+    }
+
+    /// Queue terminal control bytes even if the queue is full
+    fn send_control_bytes(&mut self, b: Bytes) {
+        // A fresh clone of the sender always has room for one message
+        let len = b.len();
+        self.stats.enqueue(len);
+        if self.snd.clone().try_send(Outgoing::Data(b)).is_err() {
+            self.stats.dequeue(len);
+        }
+        self.dirty = true;
+    }
+
+    /// Queue a chunk for the writer thread. The chunk is given back if the queue is full.
+    fn start_send_chunk(&mut self, b: Bytes) -> Result<AsyncSink<Bytes>> {
+        if let Some(ref mut limit) = self.limit {
+            if limit.poll_budget()? == 0 {
+                return Ok(AsyncSink::NotReady(b));
+            }
+        }
+        let len = b.len();
+        if len > 0 {
+            self.stats.enqueue(len);
+        }
+        let ret = self.snd.start_send(Outgoing::Data(b));
+        if len > 0 && !matches!(ret, Ok(AsyncSink::Ready)) {
+            self.stats.dequeue(len);
+        }
+        match ret {
+            Ok(AsyncSink::Ready) => {
+                if let Some(ref mut limit) = self.limit {
+                    limit.consume(len);
+                }
+                self.dirty = true;
+                Ok(AsyncSink::Ready)
+            }
+            Ok(AsyncSink::NotReady(Outgoing::Data(b))) => Ok(AsyncSink::NotReady(b)),
+            Ok(AsyncSink::NotReady(_)) => unreachable!(),
+            Err(_) => Err(self.worker_error()),
+        }
+    }
+
+    /// Queue the chunk in `pending`, if any. Returns `false` if the queue is full
+    /// and the chunk is still in `pending`.
+    #[cfg(any(feature = "csv", feature = "encoding"))]
+    fn send_pending(&mut self, pending: &mut Option<Bytes>) -> Result<bool> {
+        let b = match pending.take() {
+            Some(x) => x,
+            None => return Ok(true),
+        };
+        match self.start_send_chunk(b)? {
+            AsyncSink::Ready => Ok(true),
+            AsyncSink::NotReady(x) => {
+                *pending = Some(x);
+                Ok(false)
+            }
+        }
+    }
 }
-/// Constructor for the `ThreadedStdout`
-pub fn stdout(queue_size: usize) -> ThreadedStdout {
-    let (snd, rcv): (BBS, BBR) = futures::sync::mpsc::channel(queue_size);
+
+fn threaded_writer<F, W>(queue_size: usize, kind: StdioKind, open: F) -> ThreadedStdout
+where
+    F: FnOnce() -> W + Send + 'static,
+    W: Write + 'static,
+{
+    let (snd, rcv): (OutgoingS, OutgoingR) = futures::sync::mpsc::channel(queue_size);
+    let stats = stats::Counters::new();
+    let stats_ = stats.clone();
     let jh = std::thread::spawn(move || {
-        let sout = ::std::io::stdout();
-        let mut sout_lock = sout.lock();
-        for b in rcv.wait() {
-            if let Ok(b) = b {
-                if b.len() == 0 {
+        stats_.clone().run_worker(|| {
+            let mut sout_lock = compression::Output::Plain(stats::Counted {
+                inner: open(),
+                counters: stats_.clone(),
+            });
+            // Errors are kept for the async side, which sees the channel closed
+            for b in rcv.wait() {
+                let ret = match b {
+                    Ok(Outgoing::Data(b)) => {
+                        if b.is_empty() {
+                            break;
+                        }
+                        stats_.dequeue(b.len());
+                        sout_lock.write_all(&b).and_then(|()| {
+                            if kind.flush_each_chunk() {
+                                sout_lock.flush_chunk()
+                            } else {
+                                Ok(())
+                            }
+                        })
+                    }
+                    Ok(Outgoing::Flush) => sout_lock.flush(),
+                    Ok(Outgoing::Compress(format, level)) => {
+                        sout_lock = match sout_lock.compress(format, level) {
+                            Ok(x) => x,
+                            Err(e) => return stats_.fail(e),
+                        };
+                        Ok(())
+                    }
+                    Err(_) => break,
+                };
+                if let Err(e) = ret {
+                    stats_.fail(e);
                     break;
                 }
-                if sout_lock.write_all(&b).is_err() {
-                    break;
-                }
-                if sout_lock.flush().is_err() {
-                    break;
+            }
+            match sout_lock.finish() {
+                Ok(mut w) => {
+                    let _ = w.flush();
+                    let _ = w.write(&[]);
                 }
-            } else {
-                break;
+                Err(e) => stats_.fail(e),
             }
-        }
-        let _ = sout_lock.write(&[]);
+        })
     });
-    ThreadedStdout { snd, jh: Some(jh) }
+    ThreadedStdout {
+        snd,
+        jh: Some(jh),
+        kind,
+        dirty: false,
+        limit: None,
+        stats,
+    }
+}
+
+/// Constructor for the `ThreadedStdout`
+pub fn stdout(queue_size: usize) -> ThreadedStdout {
+    threaded_writer(queue_size, stdout_kind(), || ::std::io::stdout().lock())
 }
 
 impl AsyncWrite for ThreadedStdout {
     fn shutdown(&mut self) -> Poll<(), Error> {
-        // Signal the thread to exit.
-        match self.snd.start_send(vec![].into_boxed_slice()) {
-            Ok(AsyncSink::Ready) => (),
+        // Signal the thread to exit. Errors mean it has already exited (or is finishing
+        // the output after an earlier exit request), so it is joined anyway.
+        match self.snd.start_send(Outgoing::Data(Bytes::new())) {
+            Ok(AsyncSink::Ready) | Err(_) => (),
             Ok(AsyncSink::NotReady(_)) => return Ok(Async::NotReady),
-            Err(_) => {
-                return Ok(Async::Ready(()));
-            }
         };
-        match self.snd.poll_complete() {
-            Ok(Async::Ready(_)) => (),
-            Ok(Async::NotReady) => return Ok(Async::NotReady),
-            Err(_) => {
-                return Ok(Async::Ready(()));
-            }
-        };
-        if self.snd.close().is_err() {
-            return Ok(Async::Ready(()));
+        if let Ok(Async::NotReady) = self.snd.poll_complete() {
+            return Ok(Async::NotReady);
         };
+        let _ = self.snd.close();
         if let Some(jh) = self.jh.take() {
-            if jh.join().is_err() {
-                return Err(ErrorKind::Other.into());
-            };
+            // Panics are caught in the thread, so `join` succeeds anyway
+            let _ = jh.join();
+        }
+        if let Some(e) = self.stats.exit_error("stdout") {
+            return Err(e);
         }
         Ok(Async::Ready(()))
     }
@@ -188,52 +962,41 @@ impl Write for ThreadedStdout {
         if buf.is_empty() {
             return Ok(0);
         }
+        let buf = match self.limit {
+            Some(ref mut limit) => match limit.poll_budget()? {
+                0 => return Err(ErrorKind::WouldBlock.into()),
+                n => &buf[..n.min(buf.len())],
+            },
+            None => buf,
+        };
 
-        match self.snd.start_send(buf.to_vec().into_boxed_slice()) {
-            Ok(AsyncSink::Ready) => (),
-            Ok(AsyncSink::NotReady(_)) => return Err(ErrorKind::WouldBlock.into()),
-            Err(_) => return Err(ErrorKind::Other.into()),
+        match self.start_send_chunk(Bytes::from(buf))? {
+            AsyncSink::Ready => (),
+            AsyncSink::NotReady(_) => return Err(ErrorKind::WouldBlock.into()),
         }
 
         Ok(buf.len())
     }
     fn flush(&mut self) -> Result<()> {
-        match self.snd.poll_complete() {
-            Ok(Async::Ready(_)) => Ok(()),
-            Ok(Async::NotReady) => Err(ErrorKind::WouldBlock.into()),
-            Err(_) => Err(ErrorKind::Other.into()),
+        if self.dirty {
+            match self.snd.start_send(Outgoing::Flush) {
+                Ok(AsyncSink::Ready) => self.dirty = false,
+                Ok(AsyncSink::NotReady(_)) => return Err(ErrorKind::WouldBlock.into()),
+                Err(_) => return Err(self.worker_error()),
+            }
+        }
+        match self.poll_complete_chunks()? {
+            Async::Ready(()) => Ok(()),
+            Async::NotReady => Err(ErrorKind::WouldBlock.into()),
         }
     }
 }
 
-// XXX code duplication:
-
 /// Asynchronous stderr
 pub type ThreadedStderr = ThreadedStdout;
 /// Constructor for the `ThreadedStderr`
 pub fn stderr(queue_size: usize) -> ThreadedStderr {
-    let (snd, rcv): (BBS, BBR) = futures::sync::mpsc::channel(queue_size);
-    let jh = std::thread::spawn(move || {
-        let sout = ::std::io::stderr();
-        let mut sout_lock = sout.lock();
-        for b in rcv.wait() {
-            if let Ok(b) = b {
-                if b.len() == 0 {
-                    break;
-                }
-                if sout_lock.write_all(&b).is_err() {
-                    break;
-                }
-                if sout_lock.flush().is_err() {
-                    break;
-                }
-            } else {
-                break;
-            }
-        }
-        let _ = sout_lock.write(&[]);
-    });
-    ThreadedStdout { snd, jh: Some(jh) }
+    threaded_writer(queue_size, stderr_kind(), || ::std::io::stderr().lock())
 }
 
 /// A sendable and clonable ThreadedStdout wrapper based on `Arc<Mutex<ThreadedStdout>>`
@@ -256,7 +1019,7 @@ impl SendableStdout {
 
     /// Acquire more permanent mutex guard on stdout, like with `std::io::Stdout::lock`
     /// The returned guard also implements AsyncWrite
-    pub fn lock(&self) -> LockResult<SendableStdoutGuard> {
+    pub fn lock(&self) -> LockResult<SendableStdoutGuard<'_>> {
         match self.0.lock() {
             Ok(x) => Ok(SendableStdoutGuard(x)),
             Err(e) => Err(PoisonError::new(SendableStdoutGuard(e.into_inner()))),
@@ -264,7 +1027,7 @@ impl SendableStdout {
     }
     /// Acquire more permanent mutex guard on stdout
     /// The returned guard also implements AsyncWrite
-    pub fn try_lock(&self) -> TryLockResult<SendableStdoutGuard> {
+    pub fn try_lock(&self) -> TryLockResult<SendableStdoutGuard<'_>> {
         match self.0.try_lock() {
             Ok(x) => Ok(SendableStdoutGuard(x)),
             Err(TryLockError::Poisoned(e)) => Err(TryLockError::Poisoned(PoisonError::new(
@@ -386,7 +1149,7 @@ impl SendableStdin {
 
     /// Acquire more permanent mutex guard on stdout, like with `std::io::Stdout::lock`
     /// The returned guard also implements AsyncWrite
-    pub fn lock(&self) -> LockResult<SendableStdinGuard> {
+    pub fn lock(&self) -> LockResult<SendableStdinGuard<'_>> {
         match self.0.lock() {
             Ok(x) => Ok(SendableStdinGuard(x)),
             Err(e) => Err(PoisonError::new(SendableStdinGuard(e.into_inner()))),
@@ -394,7 +1157,7 @@ impl SendableStdin {
     }
     /// Acquire more permanent mutex guard on stdout
     /// The returned guard also implements AsyncWrite
-    pub fn try_lock(&self) -> TryLockResult<SendableStdinGuard> {
+    pub fn try_lock(&self) -> TryLockResult<SendableStdinGuard<'_>> {
         match self.0.try_lock() {
             Ok(x) => Ok(SendableStdinGuard(x)),
             Err(TryLockError::Poisoned(e)) => Err(TryLockError::Poisoned(PoisonError::new(
@@ -421,3 +1184,45 @@ impl<'a> Read for SendableStdinGuard<'a> {
     }
 }
 impl<'a> AsyncRead for SendableStdinGuard<'a> {}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Read};
+    use testutil::{block_on, chunks_reader};
+    use threaded_reader;
+
+    #[test]
+    fn peek_across_chunks() {
+        let mut s = threaded_reader(chunks_reader(&[b"ab", b"cd", b"ef"]), 1);
+        let mut buf = [0; 5];
+        assert_eq!(block_on(|| s.peek(&mut buf)).unwrap(), 5);
+        assert_eq!(&buf, b"abcde");
+        let mut buf = [0; 3];
+        assert_eq!(block_on(|| s.read(&mut buf)).unwrap(), 3);
+        assert_eq!(&buf, b"abc");
+        let mut buf = [0; 8];
+        assert_eq!(block_on(|| s.peek(&mut buf)).unwrap(), 3);
+        assert_eq!(&buf[..3], b"def");
+    }
+
+    #[test]
+    fn unread() {
+        let mut s = threaded_reader(chunks_reader(&[b"world"]), 1);
+        let mut buf = [0; 2];
+        assert_eq!(block_on(|| s.read(&mut buf)).unwrap(), 2);
+        s.unread(b"wo");
+        s.unread(b"hello ");
+        s.unread(b"");
+        let mut out = vec![];
+        loop {
+            let n = block_on(|| s.fill_buf().map(|x| x.len())).unwrap();
+            if n == 0 {
+                break;
+            }
+            let chunk = s.fill_buf().unwrap().to_vec();
+            s.consume(chunk.len());
+            out.extend(chunk);
+        }
+        assert_eq!(out, b"hello world");
+    }
+}
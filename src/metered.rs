@@ -0,0 +1,379 @@
+//! Throughput measurement
+
+use bytes::Bytes;
+use futures::{Async, Future, Poll, Stream};
+use std::collections::VecDeque;
+use std::io::{BufRead, Error, ErrorKind, Read, Result, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::Interval;
+use {StatusLine, ThreadedStdin, ThreadedStdout};
+
+/// Time span of the moving average
+const WINDOW: Duration = Duration::from_secs(5);
+/// Minimal distance between samples kept for the moving average
+const SAMPLE_EVERY: Duration = Duration::from_millis(100);
+
+/// Counters of a `Metered` handle, shared through an `Arc`
+pub struct Meter {
+    bytes: AtomicU64,
+    chunks: AtomicU64,
+    finished: AtomicBool,
+    start: Instant,
+    /// `(time, total bytes)`, oldest first
+    samples: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+/// Counters at some moment, returned by `Meter::snapshot`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeterSnapshot {
+    /// Bytes transferred
+    pub bytes: u64,
+    /// Reads or writes that transferred something
+    pub chunks: u64,
+    /// Time since the meter was created
+    pub elapsed: Duration,
+    /// Bytes per second over the last few seconds
+    pub rate: f64,
+    /// Bytes per second since the meter was created
+    pub average_rate: f64,
+    /// End of input was reached, or output was shut down
+    pub finished: bool,
+}
+
+impl Meter {
+    fn new() -> Meter {
+        let start = Instant::now();
+        Meter {
+            bytes: AtomicU64::new(0),
+            chunks: AtomicU64::new(0),
+            finished: AtomicBool::new(false),
+            start,
+            samples: Mutex::new(vec![(start, 0)].into()),
+        }
+    }
+
+    fn record(&self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        let total = self.bytes.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+        self.chunks.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        if let Ok(mut samples) = self.samples.lock() {
+            let due = match samples.back() {
+                Some(&(t, _)) => now - t >= SAMPLE_EVERY,
+                None => true,
+            };
+            if due {
+                samples.push_back((now, total));
+            }
+            // Keep one sample older than the window to measure from
+            while samples.len() > 2 && now - samples[1].0 > WINDOW {
+                samples.pop_front();
+            }
+        }
+    }
+
+    fn finish(&self) {
+        self.finished.store(true, Ordering::Relaxed);
+    }
+
+    /// Current counters and rates
+    pub fn snapshot(&self) -> MeterSnapshot {
+        let now = Instant::now();
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let elapsed = now - self.start;
+        let rate = match self.samples.lock() {
+            Ok(samples) => {
+                let (t, b) = samples.front().cloned().unwrap_or((self.start, 0));
+                per_second(bytes - b, now - t)
+            }
+            Err(_) => 0.0,
+        };
+        MeterSnapshot {
+            bytes,
+            chunks: self.chunks.load(Ordering::Relaxed),
+            elapsed,
+            rate,
+            average_rate: per_second(bytes, elapsed),
+            finished: self.finished.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Show the counters on `status` every `interval` until the metered handle finishes,
+    /// then print a summary line. Resolves to `status` back.
+    pub fn display(meter: Arc<Meter>, status: StatusLine, interval: Duration) -> MeterDisplay {
+        MeterDisplay {
+            meter,
+            status: Some(status),
+            interval: Interval::new(Instant::now(), interval),
+            summary: None,
+        }
+    }
+}
+
+fn per_second(bytes: u64, d: Duration) -> f64 {
+    let secs = d.as_secs_f64();
+    if secs > 0.0 {
+        bytes as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+/// `1234567` -> `"1.2 MiB"`
+fn human(n: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut n = n;
+    let mut unit = 0;
+    while n >= 1024.0 && unit + 1 < UNITS.len() {
+        n /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", n as u64, UNITS[0])
+    } else {
+        format!("{:.1} {}", n, UNITS[unit])
+    }
+}
+
+impl ::std::fmt::Display for MeterSnapshot {
+    /// Like `12.0 MiB 0:05 [2.4 MiB/s]`
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let secs = self.elapsed.as_secs();
+        let rate = if self.finished {
+            self.average_rate
+        } else {
+            self.rate
+        };
+        write!(
+            f,
+            "{} {}:{:02} [{}/s]",
+            human(self.bytes as f64),
+            secs / 60,
+            secs % 60,
+            human(rate)
+        )
+    }
+}
+
+/// Future returned by `Meter::display`
+pub struct MeterDisplay {
+    meter: Arc<Meter>,
+    status: Option<StatusLine>,
+    interval: Interval,
+    /// Final line not yet written
+    summary: Option<Vec<u8>>,
+}
+
+impl Future for MeterDisplay {
+    type Item = StatusLine;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<StatusLine, Error> {
+        loop {
+            if let Some(ref summary) = self.summary {
+                let status = self
+                    .status
+                    .as_mut()
+                    .expect("polled MeterDisplay after completion");
+                match status.write(summary) {
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => {
+                        return Ok(Async::NotReady)
+                    }
+                    Err(e) => return Err(e),
+                }
+                return Ok(Async::Ready(self.status.take().unwrap()));
+            }
+            let snapshot = self.meter.snapshot();
+            if snapshot.finished {
+                self.status.as_mut().unwrap().clear();
+                self.summary = Some(format!("{}\n", snapshot).into_bytes());
+                continue;
+            }
+            self.status.as_mut().unwrap().set(&snapshot.to_string())?;
+            try_ready!(self
+                .interval
+                .poll()
+                .map_err(|e| Error::new(ErrorKind::Other, e)));
+        }
+    }
+}
+
+/// Wrapper which counts bytes going through a reading or writing handle.
+/// Created by `ThreadedStdin::metered`, `ThreadedStdout::metered` or `Metered::new`.
+///
+/// The counters can be watched from another task with `meter`.
+pub struct Metered<T> {
+    inner: T,
+    meter: FinishOnDrop,
+}
+
+/// Marks the meter finished when the `Metered` handle goes away
+struct FinishOnDrop(Arc<Meter>);
+
+impl Drop for FinishOnDrop {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+impl<T> Metered<T> {
+    /// Count traffic of `inner`
+    pub fn new(inner: T) -> Metered<T> {
+        Metered {
+            inner,
+            meter: FinishOnDrop(Arc::new(Meter::new())),
+        }
+    }
+
+    /// Shared counters
+    pub fn meter(&self) -> Arc<Meter> {
+        self.meter.0.clone()
+    }
+
+    /// Current counters and rates, same as `meter().snapshot()`
+    pub fn snapshot(&self) -> MeterSnapshot {
+        self.meter.0.snapshot()
+    }
+
+    /// Access the wrapped handle
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Access the wrapped handle. Traffic through it is not counted.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Get the wrapped handle back. The meter is marked as finished.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read> Read for Metered<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.meter.0.finish();
+        }
+        self.meter.0.record(n);
+        Ok(n)
+    }
+}
+
+impl<T: BufRead> BufRead for Metered<T> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        let buf = self.inner.fill_buf()?;
+        if buf.is_empty() {
+            self.meter.0.finish();
+        }
+        Ok(buf)
+    }
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.meter.0.record(amt);
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Metered<T> {}
+
+impl<T: Stream<Item = Bytes>> Stream for Metered<T> {
+    type Item = Bytes;
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, T::Error> {
+        match try_ready!(self.inner.poll()) {
+            Some(x) => {
+                self.meter.0.record(x.len());
+                Ok(Async::Ready(Some(x)))
+            }
+            None => {
+                self.meter.0.finish();
+                Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+impl<T: Write> Write for Metered<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.meter.0.record(n);
+        Ok(n)
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for Metered<T> {
+    fn shutdown(&mut self) -> Poll<(), Error> {
+        try_ready!(self.inner.shutdown());
+        self.meter.0.finish();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl ThreadedStdin {
+    /// Count bytes read and measure throughput
+    pub fn metered(self) -> Metered<ThreadedStdin> {
+        Metered::new(self)
+    }
+}
+
+impl ThreadedStdout {
+    /// Count bytes written and measure throughput
+    pub fn metered(self) -> Metered<ThreadedStdout> {
+        Metered::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{human, per_second, Metered};
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human(0.0), "0 B");
+        assert_eq!(human(1023.0), "1023 B");
+        assert_eq!(human(1536.0), "1.5 KiB");
+        assert_eq!(human(1234567.0), "1.2 MiB");
+        assert_eq!(
+            human(3.0 * 1024.0 * 1024.0 * 1024.0 * 1024.0 * 1024.0),
+            "3072.0 TiB"
+        );
+    }
+
+    #[test]
+    fn rates() {
+        assert_eq!(per_second(100, Duration::from_millis(500)), 200.0);
+        assert_eq!(per_second(100, Duration::from_secs(0)), 0.0);
+    }
+
+    #[test]
+    fn counts() {
+        let mut r = Metered::new(&b"hello world"[..]);
+        let mut buf = [0; 4];
+        while r.read(&mut buf).unwrap() > 0 {}
+        let s = r.snapshot();
+        assert_eq!((s.bytes, s.chunks, s.finished), (11, 3, true));
+
+        let mut w = Metered::new(vec![]);
+        w.write_all(b"abc").unwrap();
+        w.write_all(b"").unwrap();
+        let meter = w.meter();
+        assert_eq!((meter.snapshot().bytes, meter.snapshot().chunks), (3, 1));
+        assert!(!meter.snapshot().finished);
+        assert_eq!(w.into_inner(), b"abc");
+        assert!(meter.snapshot().finished);
+    }
+}
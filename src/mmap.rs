@@ -0,0 +1,134 @@
+//! Thread-less stdin for the case when fd 0 is a regular file
+
+use bytes::Bytes;
+use memmap2::{Mmap, MmapOptions};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, SeekFrom};
+use std::mem::ManuallyDrop;
+use std::os::unix::io::FromRawFd;
+
+/// Length of a mapping, which may not fit into the address space on 32-bit systems
+fn map_len(len: u64) -> Result<usize> {
+    usize::try_from(len).map_err(|_| {
+        Error::new(
+            ErrorKind::Other,
+            "file is too large to map into the address space",
+        )
+    })
+}
+
+/// Content of stdin from the current file offset up to the end of file,
+/// mapped into memory when the handle was created.
+///
+/// File offset of fd 0 is moved to the consumed position when this gets dropped,
+/// so the rest of the file is still available to subsequent readers.
+pub(crate) struct MappedStdin {
+    map: Mmap,
+    base: u64,
+    pos: usize,
+    /// Queue size for a worker thread started later, e.g. by `auto_decompress`
+    pub(crate) queue_size: usize,
+}
+
+impl MappedStdin {
+    /// `Ok(None)` means there is nothing to map: we are already at the end of file
+    pub(crate) fn open(queue_size: usize) -> Result<Option<MappedStdin>> {
+        // Not owned by us, so must not be closed
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(0) });
+        let base = unsafe { ::libc::lseek(0, 0, ::libc::SEEK_CUR) };
+        if base < 0 {
+            return Err(Error::last_os_error());
+        }
+        let base = base as u64;
+        let len = file.metadata()?.len();
+        if len <= base {
+            return Ok(None);
+        }
+        let map = unsafe {
+            MmapOptions::new()
+                .offset(base)
+                .len(map_len(len - base)?)
+                .map(&*file)?
+        };
+        Ok(Some(MappedStdin {
+            map,
+            base,
+            pos: 0,
+            queue_size,
+        }))
+    }
+
+    /// Move the read position. Seeking before the mapped region remaps the file.
+    pub(crate) fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let cur = self.base + self.pos as u64;
+        let end = self.base + self.map.len() as u64;
+        let target = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::Current(d) if d >= 0 => cur.checked_add(d as u64),
+            SeekFrom::Current(d) => cur.checked_sub(d.unsigned_abs()),
+            SeekFrom::End(d) if d >= 0 => end.checked_add(d as u64),
+            SeekFrom::End(d) => end.checked_sub(d.unsigned_abs()),
+        };
+        let target = match target {
+            Some(x) => x,
+            None => return Err(ErrorKind::InvalidInput.into()),
+        };
+        if target < self.base {
+            let file = ManuallyDrop::new(unsafe { File::from_raw_fd(0) });
+            self.map = unsafe {
+                MmapOptions::new()
+                    .offset(target)
+                    .len(map_len(end - target)?)
+                    .map(&*file)?
+            };
+            self.base = target;
+            self.pos = 0;
+        } else {
+            // Past the end of file, reads just return EOF
+            self.pos = ::std::cmp::min(target - self.base, self.map.len() as u64) as usize;
+        }
+        Ok(target)
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.map.len() - self.pos
+    }
+
+    /// Move the read position back by `n` bytes that were read, but not used
+    pub(crate) fn unconsume(&mut self, n: usize) {
+        self.pos -= ::std::cmp::min(n, self.pos);
+    }
+
+    /// Copy directly from the mapping to `buf`. Returns 0 at the end of file.
+    pub(crate) fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = ::std::cmp::min(buf.len(), self.remaining());
+        buf[0..n].copy_from_slice(&self.map[self.pos..self.pos + n]);
+        self.pos += n;
+        n
+    }
+
+    /// Copy of the next chunk of at most `max` bytes. Empty chunk means end of file.
+    pub(crate) fn chunk(&mut self, max: usize) -> Bytes {
+        let n = ::std::cmp::min(max, self.remaining());
+        let b = Bytes::from(&self.map[self.pos..self.pos + n]);
+        self.pos += n;
+        b
+    }
+}
+
+/// Used when the mapping is handed over to a worker thread, e.g. for decompression
+impl Read for MappedStdin {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(MappedStdin::read(self, buf))
+    }
+}
+
+impl Drop for MappedStdin {
+    fn drop(&mut self) {
+        let off = self.base + self.pos as u64;
+        unsafe {
+            ::libc::lseek(0, off as ::libc::off_t, ::libc::SEEK_SET);
+        }
+    }
+}
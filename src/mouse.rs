@@ -0,0 +1,333 @@
+//! xterm mouse reporting
+
+use bytes::Bytes;
+use futures::{Async, Poll};
+use keys::Modifiers;
+use std::io::{Result, Write};
+use tokio_io::AsyncWrite;
+use ThreadedStdout;
+
+/// Mouse button
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MouseButton {
+    /// Left button
+    Left,
+    /// Middle button (wheel click)
+    Middle,
+    /// Right button
+    Right,
+}
+
+/// What happened with the mouse
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MouseAction {
+    /// Button pressed
+    Down(MouseButton),
+    /// Button released. The legacy X10 protocol does not tell which one.
+    Up(Option<MouseButton>),
+    /// Moved with a button held down
+    Drag(MouseButton),
+    /// Moved with no buttons held down
+    Move,
+    /// Wheel scrolled up
+    ScrollUp,
+    /// Wheel scrolled down
+    ScrollDown,
+    /// Wheel tilted left
+    ScrollLeft,
+    /// Wheel tilted right
+    ScrollRight,
+}
+
+/// Mouse event reported by the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MouseEvent {
+    /// What happened
+    pub action: MouseAction,
+    /// Column, starting from 0
+    pub column: u16,
+    /// Row, starting from 0
+    pub row: u16,
+    /// Modifier keys held down
+    pub modifiers: Modifiers,
+}
+
+/// Decode a button code and 1-based coordinates. `None` for buttons we do not know.
+fn decode(code: u32, column: u32, row: u32, release: bool) -> Option<MouseEvent> {
+    let button = match code & 3 {
+        0 => Some(MouseButton::Left),
+        1 => Some(MouseButton::Middle),
+        2 => Some(MouseButton::Right),
+        _ => None,
+    };
+    let action = match (code & 0xc0, code & 32 != 0, button) {
+        (0x40, _, _) => match code & 3 {
+            0 => MouseAction::ScrollUp,
+            1 => MouseAction::ScrollDown,
+            2 => MouseAction::ScrollLeft,
+            _ => MouseAction::ScrollRight,
+        },
+        (0, true, Some(b)) => MouseAction::Drag(b),
+        (0, true, None) => MouseAction::Move,
+        (0, false, b) if release || b.is_none() => MouseAction::Up(b),
+        (0, false, Some(b)) => MouseAction::Down(b),
+        _ => return None,
+    };
+    Some(MouseEvent {
+        action,
+        column: column.saturating_sub(1).min(u16::MAX as u32) as u16,
+        row: row.saturating_sub(1).min(u16::MAX as u32) as u16,
+        modifiers: Modifiers {
+            shift: code & 4 != 0,
+            alt: code & 8 != 0,
+            ctrl: code & 16 != 0,
+        },
+    })
+}
+
+/// SGR report `ESC [ < code ; column ; row M` (press) or `m` (release), given the parameters
+pub(crate) fn parse_sgr(params: &[u32], fin: u8) -> Option<MouseEvent> {
+    match *params {
+        [code, column, row] => decode(code, column, row, fin == b'm'),
+        _ => None,
+    }
+}
+
+/// X10 report `ESC [ M` followed by three bytes, each a value plus 32
+pub(crate) fn parse_x10(b: [u8; 3]) -> Option<MouseEvent> {
+    let v = |x: u8| u32::from(x.saturating_sub(32));
+    decode(v(b[0]), v(b[1]), v(b[2]), false)
+}
+
+const ENABLE: &[u8] = b"\x1b[?1000h\x1b[?1002h\x1b[?1006h";
+const DISABLE: &[u8] = b"\x1b[?1006l\x1b[?1002l\x1b[?1000l";
+
+/// `ThreadedStdout` with mouse reporting turned on. Created by `ThreadedStdout::mouse_capture`.
+///
+/// Reports arrive as `InputEvent::Mouse` from `ThreadedStdin::key_events`.
+/// Reporting is turned off on shutdown, on drop and by `into_inner`.
+/// Nothing is written if stdout is not a terminal.
+pub struct MouseCapture {
+    inner: Option<ThreadedStdout>,
+    enabled: bool,
+}
+
+impl MouseCapture {
+    /// Whether reporting was turned on, i.e. stdout is a terminal
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Turn reporting off and get `ThreadedStdout` back
+    pub fn into_inner(mut self) -> ThreadedStdout {
+        self.disable();
+        self.inner.take().unwrap()
+    }
+
+    fn disable(&mut self) {
+        if self.enabled {
+            self.enabled = false;
+            if let Some(ref mut x) = self.inner {
+                x.send_control_bytes(Bytes::from_static(DISABLE));
+            }
+        }
+    }
+
+    fn inner(&mut self) -> &mut ThreadedStdout {
+        self.inner.as_mut().unwrap()
+    }
+}
+
+impl Write for MouseCapture {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.inner().write(buf)
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.inner().flush()
+    }
+}
+
+impl AsyncWrite for MouseCapture {
+    fn shutdown(&mut self) -> Poll<(), ::std::io::Error> {
+        self.disable();
+        try_ready!(self.inner().shutdown());
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for MouseCapture {
+    fn drop(&mut self) {
+        self.disable();
+    }
+}
+
+impl ThreadedStdout {
+    /// Turn on xterm mouse reporting of clicks, drags and scrolling, using the SGR (1006)
+    /// protocol where the terminal supports it and the X10 one otherwise.
+    /// Does nothing if stdout is not a terminal.
+    pub fn mouse_capture(mut self) -> MouseCapture {
+        let enabled = self.kind().is_tty();
+        if enabled {
+            self.send_control_bytes(Bytes::from_static(ENABLE));
+        }
+        MouseCapture {
+            inner: Some(self),
+            enabled,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::poll_fn;
+    use futures::Future;
+    use testutil::{block_on, SharedBuf};
+    use StdioKind;
+
+    fn capture(kind: StdioKind) -> (MouseCapture, SharedBuf) {
+        let out = SharedBuf::default();
+        let out_ = out.clone();
+        let stdout = ::threaded_writer(4, kind, move || out_);
+        (stdout.mouse_capture(), out)
+    }
+
+    #[test]
+    fn only_on_terminal() {
+        let (mut m, out) = capture(StdioKind::Tty);
+        assert!(m.is_enabled());
+        block_on(|| m.write(b"x")).unwrap();
+        poll_fn(|| m.shutdown()).wait().unwrap();
+        assert_eq!(out.contents(), [ENABLE, b"x", DISABLE].concat());
+
+        let (mut m, out) = capture(StdioKind::Pipe);
+        assert!(!m.is_enabled());
+        block_on(|| m.write(b"x")).unwrap();
+        poll_fn(|| m.shutdown()).wait().unwrap();
+        assert_eq!(out.contents(), b"x");
+    }
+
+    fn ev(action: MouseAction, column: u16, row: u16, modifiers: Modifiers) -> MouseEvent {
+        MouseEvent {
+            action,
+            column,
+            row,
+            modifiers,
+        }
+    }
+
+    #[test]
+    fn decode_codes() {
+        let none = Modifiers::default();
+        let table = [
+            (
+                (0, 1, 1, false),
+                Some(ev(MouseAction::Down(MouseButton::Left), 0, 0, none)),
+            ),
+            (
+                (1, 5, 7, false),
+                Some(ev(MouseAction::Down(MouseButton::Middle), 4, 6, none)),
+            ),
+            (
+                (2, 1, 1, true),
+                Some(ev(MouseAction::Up(Some(MouseButton::Right)), 0, 0, none)),
+            ),
+            (
+                (3, 1, 1, false),
+                Some(ev(MouseAction::Up(None), 0, 0, none)),
+            ),
+            (
+                (32, 2, 3, false),
+                Some(ev(MouseAction::Drag(MouseButton::Left), 1, 2, none)),
+            ),
+            ((35, 1, 1, false), Some(ev(MouseAction::Move, 0, 0, none))),
+            (
+                (64, 1, 1, false),
+                Some(ev(MouseAction::ScrollUp, 0, 0, none)),
+            ),
+            (
+                (65, 1, 1, false),
+                Some(ev(MouseAction::ScrollDown, 0, 0, none)),
+            ),
+            (
+                (66, 1, 1, false),
+                Some(ev(MouseAction::ScrollLeft, 0, 0, none)),
+            ),
+            (
+                (67, 1, 1, false),
+                Some(ev(MouseAction::ScrollRight, 0, 0, none)),
+            ),
+            ((128, 1, 1, false), None),
+            // Shift, Alt and Ctrl bits
+            (
+                (28, 1, 1, false),
+                Some(ev(
+                    MouseAction::Down(MouseButton::Left),
+                    0,
+                    0,
+                    Modifiers {
+                        shift: true,
+                        alt: true,
+                        ctrl: true,
+                    },
+                )),
+            ),
+            // 0 is not a valid coordinate, huge ones are clamped
+            (
+                (0, 0, 100_000, false),
+                Some(ev(MouseAction::Down(MouseButton::Left), 0, u16::MAX, none)),
+            ),
+        ];
+        for &((code, column, row, release), expected) in &table {
+            assert_eq!(
+                decode(code, column, row, release),
+                expected,
+                "code {}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn sgr() {
+        let down = ev(
+            MouseAction::Down(MouseButton::Left),
+            9,
+            19,
+            Modifiers::default(),
+        );
+        let up = ev(
+            MouseAction::Up(Some(MouseButton::Left)),
+            9,
+            19,
+            Modifiers::default(),
+        );
+        assert_eq!(parse_sgr(&[0, 10, 20], b'M'), Some(down));
+        assert_eq!(parse_sgr(&[0, 10, 20], b'm'), Some(up));
+        assert_eq!(parse_sgr(&[0, 10], b'M'), None);
+        assert_eq!(parse_sgr(&[0, 10, 20, 1], b'M'), None);
+    }
+
+    #[test]
+    fn x10() {
+        let none = Modifiers::default();
+        assert_eq!(
+            parse_x10([32, 33, 34]),
+            Some(ev(MouseAction::Down(MouseButton::Left), 0, 1, none))
+        );
+        // Release does not say which button
+        assert_eq!(
+            parse_x10([35, 33, 33]),
+            Some(ev(MouseAction::Up(None), 0, 0, none))
+        );
+        assert_eq!(
+            parse_x10([96, 255, 33]),
+            Some(ev(MouseAction::ScrollUp, 222, 0, none))
+        );
+        // Bytes below 32 count as 0
+        assert_eq!(
+            parse_x10([0, 0, 0]),
+            Some(ev(MouseAction::Down(MouseButton::Left), 0, 0, none))
+        );
+    }
+}
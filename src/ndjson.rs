@@ -0,0 +1,304 @@
+//! Newline-delimited JSON over stdin and stdout (requires `ndjson` cargo feature)
+//!
+//! ```rust,no_run
+//! extern crate futures;
+//! extern crate serde_json;
+//! extern crate tokio;
+//! extern crate tokio_stdin_stdout;
+//!
+//! use futures::{Future, Stream};
+//!
+//! let input = tokio_stdin_stdout::stdin(0).ndjson::<serde_json::Value>();
+//! let output = tokio_stdin_stdout::stdout(0).make_sendable().ndjson();
+//! let f = input.forward(output).map(|_| ()).map_err(|e| eprintln!("{}", e));
+//! tokio::runtime::current_thread::Runtime::new().unwrap().block_on(f).unwrap();
+//! ```
+
+use bytes::Bytes;
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use split::is_oversized;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+use tokio_io::AsyncWrite;
+use {Records, SendableStdout, ThreadedStdin};
+
+/// Limit on the length of a line used by `ThreadedStdin::ndjson`, 16 MiB
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 16 << 20;
+
+/// Details of a line which failed to parse.
+///
+/// Returned as the inner error of `std::io::Error` with `ErrorKind::InvalidData`.
+#[derive(Debug)]
+pub struct ParseError {
+    /// 1-based number of the offending line
+    pub line: u64,
+    /// Offset of the beginning of the line, counted from where `ndjson` was called.
+    /// Not exact after a line longer than the limit, whose length is unknown.
+    pub offset: u64,
+    /// What went wrong
+    pub error: ::serde_json::Error,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "JSON parse error at line {} (byte offset {}): {}",
+            self.line, self.offset, self.error
+        )
+    }
+}
+
+impl ::std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn (::std::error::Error) + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Stream of values, one per line of stdin. Created by `ThreadedStdin::ndjson`.
+///
+/// Empty (whitespace-only) lines are skipped. Lines longer than the limit
+/// are reported as `ErrorKind::InvalidData` errors without a `ParseError` inside.
+pub struct NdjsonStdin<T> {
+    lines: Records,
+    line: u64,
+    offset: u64,
+    lenient: bool,
+    skipped: u64,
+    _pd: PhantomData<fn() -> T>,
+}
+
+impl<T> NdjsonStdin<T> {
+    /// Skip lines which fail to parse or are too long instead of returning an error.
+    /// Read errors are still returned.
+    pub fn lenient(mut self) -> NdjsonStdin<T> {
+        self.lenient = true;
+        self
+    }
+    /// Number of lines skipped in lenient mode so far
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+impl<T: DeserializeOwned> Stream for NdjsonStdin<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<T>, Error> {
+        loop {
+            let line = match self.lines.poll() {
+                Ok(Async::Ready(Some(x))) => x,
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                // Too long line, skipped by `lines`
+                Err(ref e) if is_oversized(e) && self.lenient => {
+                    self.line += 1;
+                    self.skipped += 1;
+                    continue;
+                }
+                Err(e) => {
+                    if is_oversized(&e) {
+                        self.line += 1;
+                    }
+                    return Err(e);
+                }
+            };
+            self.line += 1;
+            let offset = self.offset;
+            self.offset += line.len() as u64 + 1;
+            if line.iter().all(|c| c.is_ascii_whitespace()) {
+                continue;
+            }
+            match ::serde_json::from_slice(&line) {
+                Ok(x) => return Ok(Async::Ready(Some(x))),
+                Err(_) if self.lenient => self.skipped += 1,
+                Err(error) => {
+                    let e = ParseError {
+                        line: self.line,
+                        offset,
+                        error,
+                    };
+                    return Err(Error::new(ErrorKind::InvalidData, e));
+                }
+            }
+        }
+    }
+}
+
+/// Sink of values, each written to stdout as a line of JSON. Created by `SendableStdout::ndjson`.
+///
+/// Each line is sent to the worker thread as a single chunk, so lines written
+/// from multiple tasks via clones of the `SendableStdout` do not get mixed up.
+pub struct NdjsonStdout<T> {
+    inner: SendableStdout,
+    _pd: PhantomData<fn(T)>,
+}
+
+impl<T: Serialize> Sink for NdjsonStdout<T> {
+    type SinkItem = T;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: T) -> StartSend<T, Error> {
+        let mut line = ::serde_json::to_vec(&item).map_err(|e| Error::new(ErrorKind::Other, e))?;
+        line.push(b'\n');
+        let mut l = self
+            .inner
+            .0
+            .lock()
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+        match l.start_send_chunk(Bytes::from(line))? {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(_) => Ok(AsyncSink::NotReady(item)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        let mut l = self
+            .inner
+            .0
+            .lock()
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+        l.poll_complete_chunks()
+    }
+
+    fn close(&mut self) -> Poll<(), Error> {
+        try_ready!(self.poll_complete());
+        self.inner.shutdown()
+    }
+}
+
+impl ThreadedStdin {
+    /// Turn into a stream of JSON values, one per line.
+    ///
+    /// Lines are split on the worker thread, see `split_by`.
+    /// Parse errors are reported as `ErrorKind::InvalidData` with a `ParseError` inside.
+    /// Lines may be up to `DEFAULT_MAX_LINE_LENGTH` long.
+    pub fn ndjson<T: DeserializeOwned>(self) -> NdjsonStdin<T> {
+        self.ndjson_with_max_line(DEFAULT_MAX_LINE_LENGTH)
+    }
+
+    /// Like `ndjson`, but with lines up to `max_line_length` bytes long
+    pub fn ndjson_with_max_line<T: DeserializeOwned>(
+        self,
+        max_line_length: usize,
+    ) -> NdjsonStdin<T> {
+        NdjsonStdin {
+            lines: self.split_by(b'\n', max_line_length),
+            line: 0,
+            offset: 0,
+            lenient: false,
+            skipped: 0,
+            _pd: PhantomData,
+        }
+    }
+}
+
+impl SendableStdout {
+    /// Turn into a sink of values, each serialized as JSON on its own line
+    pub fn ndjson<T: Serialize>(self) -> NdjsonStdout<T> {
+        NdjsonStdout {
+            inner: self,
+            _pd: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NdjsonStdin, ParseError};
+    use futures::future::poll_fn;
+    use futures::{Future, Sink, Stream};
+    use serde_json::Value;
+    use std::io::{self, Error, ErrorKind, Read};
+    use testutil::{chunks_reader, SharedBuf};
+    use {threaded_reader, StdioKind};
+
+    const INPUT: &[&[u8]] = &[b"1\n\n[2,", b"3]\n{oops\n", b" \n\"four\""];
+
+    fn results(s: NdjsonStdin<Value>) -> Vec<Result<String, Error>> {
+        s.wait().map(|x| x.map(|v| v.to_string())).collect()
+    }
+
+    fn parse_error(e: &Error) -> &ParseError {
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        e.get_ref().unwrap().downcast_ref::<ParseError>().unwrap()
+    }
+
+    #[test]
+    fn values_and_errors() {
+        let r = results(threaded_reader(chunks_reader(INPUT), 1).ndjson());
+        assert_eq!(r.len(), 4);
+        assert_eq!(r[0].as_ref().unwrap(), "1");
+        assert_eq!(r[1].as_ref().unwrap(), "[2,3]");
+        let e = parse_error(r[2].as_ref().unwrap_err());
+        assert_eq!((e.line, e.offset), (4, 9));
+        assert_eq!(r[3].as_ref().unwrap(), "\"four\"");
+    }
+
+    #[test]
+    fn lenient() {
+        let mut s = threaded_reader(chunks_reader(INPUT), 1)
+            .ndjson::<Value>()
+            .lenient();
+        let values: Vec<String> = s.by_ref().wait().map(|x| x.unwrap().to_string()).collect();
+        assert_eq!(values, ["1", "[2,3]", "\"four\""]);
+        assert_eq!(s.skipped(), 1);
+    }
+
+    #[test]
+    fn long_lines() {
+        let input: &[&[u8]] = &[b"1\n123456", b"789\n{\n2\n"];
+        let r = results(threaded_reader(chunks_reader(input), 1).ndjson_with_max_line(4));
+        assert_eq!(r.len(), 4);
+        assert_eq!(r[0].as_ref().unwrap(), "1");
+        let e = r[1].as_ref().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(e.get_ref().unwrap().downcast_ref::<ParseError>().is_none());
+        assert_eq!(parse_error(r[2].as_ref().unwrap_err()).line, 3);
+        assert_eq!(r[3].as_ref().unwrap(), "2");
+
+        let mut s = threaded_reader(chunks_reader(input), 1)
+            .ndjson_with_max_line::<Value>(4)
+            .lenient();
+        let values: Vec<String> = s.by_ref().wait().map(|x| x.unwrap().to_string()).collect();
+        assert_eq!(values, ["1", "2"]);
+        assert_eq!(s.skipped(), 2);
+    }
+
+    /// Reader failing like a corrupt compressed stream
+    struct Corrupt;
+
+    impl Read for Corrupt {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(Error::new(ErrorKind::InvalidData, "corrupt"))
+        }
+    }
+
+    #[test]
+    fn lenient_read_error() {
+        let input = chunks_reader(&[b"1\n{\n"]).chain(Corrupt);
+        let mut s = threaded_reader(input, 1).ndjson::<Value>().lenient();
+        let r: Vec<_> = s.by_ref().wait().take(2).collect();
+        assert_eq!(r[0].as_ref().unwrap(), &Value::from(1));
+        let e = r[1].as_ref().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "corrupt");
+        assert_eq!(s.skipped(), 1);
+    }
+
+    #[test]
+    fn sink() {
+        let out = SharedBuf::default();
+        let out_ = out.clone();
+        let stdout = ::threaded_writer(4, StdioKind::Pipe, move || out_).make_sendable();
+        let mut sink = stdout.ndjson();
+        sink = sink.send(Value::from(1)).wait().unwrap();
+        sink = sink.send(Value::from(vec!["a", "b"])).wait().unwrap();
+        poll_fn(|| sink.close()).wait().unwrap();
+        assert_eq!(out.contents(), b"1\n[\"a\",\"b\"]\n");
+    }
+}
@@ -0,0 +1,169 @@
+//! Reading passwords without echo
+
+use bytes::Bytes;
+use futures::{Async, AsyncSink, Future, Poll};
+use std::fmt;
+use std::io::{BufRead, Error, ErrorKind};
+use std::sync::atomic::{compiler_fence, Ordering};
+use {ThreadedStderr, ThreadedStdin};
+
+/// Secret text. This value is overwritten with zeroes when dropped.
+///
+/// Copies of the input made on the way, in the worker thread's read buffer
+/// and in chunks received from it, are not wiped.
+pub struct Password(Vec<u8>);
+
+impl Password {
+    /// The secret
+    pub fn as_str(&self) -> &str {
+        // Checked when reading finished
+        unsafe { ::std::str::from_utf8_unchecked(&self.0) }
+    }
+
+    /// Append without leaving copies behind in freed memory
+    fn extend(&mut self, data: &[u8]) {
+        if self.0.capacity() - self.0.len() < data.len() {
+            let mut bigger = Vec::with_capacity((self.0.len() + data.len()).max(64) * 2);
+            bigger.extend_from_slice(&self.0);
+            wipe(&mut self.0);
+            self.0 = bigger;
+        }
+        self.0.extend_from_slice(data);
+    }
+}
+
+fn wipe(v: &mut Vec<u8>) {
+    for b in v.iter_mut() {
+        unsafe { ::std::ptr::write_volatile(b, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+    v.clear();
+}
+
+impl Drop for Password {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Password(..)")
+    }
+}
+
+/// Future returned by `ThreadedStdin::read_password`
+pub struct ReadPassword {
+    inner: Option<(ThreadedStdin, ThreadedStderr)>,
+    prompt: Option<Bytes>,
+    started: bool,
+    #[cfg(unix)]
+    echo: Option<::term::RawModeGuard>,
+    line: Password,
+}
+
+impl ReadPassword {
+    /// Read until a newline. Returns `true` if one was found, `false` on end of input.
+    fn poll_line(&mut self) -> Poll<bool, Error> {
+        let stdin = &mut self.inner.as_mut().unwrap().0;
+        loop {
+            let (n, found) = {
+                let buf = match stdin.fill_buf() {
+                    Ok(x) => x,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                    Err(e) => return Err(e),
+                };
+                if buf.is_empty() {
+                    return Ok(Async::Ready(false));
+                }
+                match buf.iter().position(|&c| c == b'\n') {
+                    Some(i) => {
+                        self.line.extend(&buf[..i]);
+                        (i + 1, true)
+                    }
+                    None => {
+                        self.line.extend(buf);
+                        (buf.len(), false)
+                    }
+                }
+            };
+            stdin.consume(n);
+            if found {
+                return Ok(Async::Ready(true));
+            }
+        }
+    }
+}
+
+impl Future for ReadPassword {
+    type Item = (ThreadedStdin, ThreadedStderr, Password);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Error> {
+        if !self.started {
+            self.started = true;
+            #[cfg(unix)]
+            {
+                if self.inner.as_ref().unwrap().0.kind().is_tty() {
+                    self.echo = Some(::term::RawModeGuard::echo_off(0)?);
+                }
+            }
+        }
+        {
+            let stderr = &mut self
+                .inner
+                .as_mut()
+                .expect("polled ReadPassword after completion")
+                .1;
+            if let Some(prompt) = self.prompt.take() {
+                if let AsyncSink::NotReady(x) = stderr.start_send_chunk(prompt)? {
+                    self.prompt = Some(x);
+                    return Ok(Async::NotReady);
+                }
+            }
+            stderr.poll_complete_chunks()?;
+        }
+        let found = try_ready!(self.poll_line());
+        if !found && self.line.0.is_empty() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        if self.line.0.last() == Some(&b'\r') {
+            self.line.0.pop();
+        }
+        if ::std::str::from_utf8(&self.line.0).is_err() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "password is not valid UTF-8",
+            ));
+        }
+        #[cfg(unix)]
+        {
+            self.echo = None;
+        }
+        let (stdin, stderr) = self.inner.take().unwrap();
+        let line = ::std::mem::replace(&mut self.line, Password(Vec::new()));
+        Ok(Async::Ready((stdin, stderr, line)))
+    }
+}
+
+impl ThreadedStdin {
+    /// Write `prompt` to `stderr` and read a line with echo turned off.
+    /// Resolves to stdin and stderr back and the line without the line terminator.
+    ///
+    /// Echo is turned back on when the future completes or is dropped.
+    /// When stdin is not a terminal, the line is just read.
+    pub fn read_password(self, stderr: ThreadedStderr, prompt: &str) -> ReadPassword {
+        ReadPassword {
+            inner: Some((self, stderr)),
+            prompt: if prompt.is_empty() {
+                None
+            } else {
+                Some(Bytes::from(prompt))
+            },
+            started: false,
+            #[cfg(unix)]
+            echo: None,
+            line: Password(Vec::new()),
+        }
+    }
+}
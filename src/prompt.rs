@@ -0,0 +1,619 @@
+//! Interactive line editor
+
+use bytes::Bytes;
+use futures::{Async, AsyncSink, Future, Poll};
+use keys::{self, InputEvent, KeyCode, Parsed};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio_timer::Delay;
+use {ThreadedStdin, ThreadedStdout};
+
+/// Outcome of `Prompt::read_line`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PromptResult {
+    /// Line entered, without the line terminator
+    Line(String),
+    /// Ctrl-C was pressed
+    Interrupted,
+    /// Ctrl-D was pressed on an empty line, or end of input
+    Eof,
+}
+
+/// Line editor with history, reading from `ThreadedStdin` and echoing to `ThreadedStdout`.
+///
+/// Supports cursor movement (arrows, Home/End, Ctrl-A/E/B/F), Backspace/Delete,
+/// Ctrl-K/Ctrl-U and history browsing (Up/Down, Ctrl-P/N).
+/// Escape sequences are decoded like `ThreadedStdin::key_events` does, so editing
+/// needs a tokio timer to tell a lone ESC from the start of a sequence.
+/// When stdin is not a terminal, lines are read as is and the prompt is not shown.
+pub struct Prompt {
+    stdin: ThreadedStdin,
+    stdout: ThreadedStdout,
+    history: Vec<String>,
+    history_file: Option<PathBuf>,
+    max_history: usize,
+}
+
+impl Prompt {
+    /// Create a line editor. Input must come from the terminal for editing to work.
+    pub fn new(stdin: ThreadedStdin, stdout: ThreadedStdout) -> Prompt {
+        Prompt {
+            stdin,
+            stdout,
+            history: Vec::new(),
+            history_file: None,
+            max_history: 1000,
+        }
+    }
+
+    /// Load history from `path` (if it exists) and append each entered line to it.
+    /// Errors appending to the file are ignored.
+    pub fn history_file<P: Into<PathBuf>>(mut self, path: P) -> Result<Prompt> {
+        let path = path.into();
+        match File::open(&path) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    self.add_history_entry(line?);
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        self.history_file = Some(path);
+        Ok(self)
+    }
+
+    /// How many entries to keep in memory, 1000 by default. The history file is not truncated.
+    pub fn max_history(mut self, n: usize) -> Prompt {
+        self.max_history = n;
+        let excess = self.history.len().saturating_sub(n);
+        self.history.drain(..excess);
+        self
+    }
+
+    /// Entries of history, oldest first
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Add an entry to history, unless it is empty or the same as the last one
+    pub fn add_history_entry(&mut self, line: String) -> bool {
+        if line.trim().is_empty() || self.history.last() == Some(&line) || self.max_history == 0 {
+            return false;
+        }
+        if self.history.len() >= self.max_history {
+            self.history.remove(0);
+        }
+        self.history.push(line);
+        true
+    }
+
+    /// Whether line editing is used, i.e. stdin is a terminal
+    pub fn is_interactive(&self) -> bool {
+        cfg!(unix) && self.stdin.kind().is_tty()
+    }
+
+    /// Show `prompt` and read a line. Resolves to this `Prompt` back and the outcome.
+    ///
+    /// Entered lines are added to history.
+    pub fn read_line(self, prompt: &str) -> ReadLine {
+        let hist_pos = self.history.len();
+        ReadLine {
+            inner: Some(self),
+            prompt: prompt.to_string(),
+            started: false,
+            #[cfg(unix)]
+            raw: None,
+            line: Vec::new(),
+            cursor: 0,
+            hist_pos,
+            saved: Vec::new(),
+            pending: Vec::new(),
+            delay: None,
+            out: Vec::new(),
+            done: None,
+        }
+    }
+
+    /// Get stdin and stdout back
+    pub fn into_inner(self) -> (ThreadedStdin, ThreadedStdout) {
+        (self.stdin, self.stdout)
+    }
+
+    /// Add an entered line to history. Failing to write the history file
+    /// must not lose the line, so such errors are ignored.
+    fn remember(&mut self, line: &str) {
+        if !self.add_history_entry(line.to_string()) {
+            return;
+        }
+        if let Some(ref path) = self.history_file {
+            let _ = append_line(path, line);
+        }
+    }
+}
+
+fn append_line(path: &Path, line: &str) -> Result<()> {
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(f, "{}", line)
+}
+
+/// Editing actions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    KillToEnd,
+    KillToStart,
+    Interrupt,
+    EofOrDelete,
+    Ignored,
+}
+
+impl Key {
+    fn from_event(ev: &InputEvent) -> Key {
+        let k = match *ev {
+            InputEvent::Key(k) => k,
+            _ => return Key::Ignored,
+        };
+        if k.modifiers.alt {
+            return Key::Ignored;
+        }
+        match k.code {
+            KeyCode::Char(c) if k.modifiers.ctrl => match c {
+                'a' => Key::Home,
+                'b' => Key::Left,
+                'c' => Key::Interrupt,
+                'd' => Key::EofOrDelete,
+                'e' => Key::End,
+                'f' => Key::Right,
+                'h' => Key::Backspace,
+                'k' => Key::KillToEnd,
+                'n' => Key::Down,
+                'p' => Key::Up,
+                'u' => Key::KillToStart,
+                _ => Key::Ignored,
+            },
+            KeyCode::Char(c) => Key::Char(c),
+            KeyCode::Enter => Key::Enter,
+            KeyCode::Backspace => Key::Backspace,
+            KeyCode::Delete => Key::Delete,
+            KeyCode::Left => Key::Left,
+            KeyCode::Right => Key::Right,
+            KeyCode::Up => Key::Up,
+            KeyCode::Down => Key::Down,
+            KeyCode::Home => Key::Home,
+            KeyCode::End => Key::End,
+            _ => Key::Ignored,
+        }
+    }
+}
+
+/// Future returned by `Prompt::read_line`
+pub struct ReadLine {
+    inner: Option<Prompt>,
+    prompt: String,
+    started: bool,
+    #[cfg(unix)]
+    raw: Option<::term::RawModeGuard>,
+    line: Vec<char>,
+    cursor: usize,
+    /// Position in history; equal to its length when editing a new line
+    hist_pos: usize,
+    /// The new line, while browsing history
+    saved: Vec<char>,
+    /// Input bytes not yet decoded into keys
+    pending: Vec<u8>,
+    /// Waits for the rest of an escape sequence
+    delay: Option<Delay>,
+    /// Output not yet sent to stdout
+    out: Vec<u8>,
+    done: Option<PromptResult>,
+}
+
+impl ReadLine {
+    fn redraw(&mut self) {
+        self.out.push(b'\r');
+        self.out.extend_from_slice(self.prompt.as_bytes());
+        let s: String = self.line.iter().collect();
+        self.out.extend_from_slice(s.as_bytes());
+        self.out.extend_from_slice(b"\x1b[K");
+        let tail = self.line.len() - self.cursor;
+        if tail > 0 {
+            self.out
+                .extend_from_slice(format!("\x1b[{}D", tail).as_bytes());
+        }
+    }
+
+    fn history_go(&mut self, pos: usize) {
+        let history = &self.inner.as_ref().unwrap().history;
+        if self.hist_pos == history.len() {
+            self.saved = self.line.clone();
+        }
+        self.line = match history.get(pos) {
+            Some(x) => x.chars().collect(),
+            None => self.saved.clone(),
+        };
+        self.hist_pos = pos;
+        self.cursor = self.line.len();
+    }
+
+    fn handle(&mut self, key: Key) {
+        match key {
+            Key::Char(c) => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            Key::Enter => {
+                self.out.extend_from_slice(b"\r\n");
+                self.done = Some(PromptResult::Line(self.line.iter().collect()));
+                return;
+            }
+            Key::Interrupt => {
+                self.out.extend_from_slice(b"^C\r\n");
+                self.done = Some(PromptResult::Interrupted);
+                return;
+            }
+            Key::EofOrDelete if self.line.is_empty() => {
+                self.out.extend_from_slice(b"\r\n");
+                self.done = Some(PromptResult::Eof);
+                return;
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            Key::Delete | Key::EofOrDelete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            Key::Left if self.cursor > 0 => self.cursor -= 1,
+            Key::Right if self.cursor < self.line.len() => self.cursor += 1,
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.line.len(),
+            Key::KillToEnd => self.line.truncate(self.cursor),
+            Key::KillToStart => {
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::Up if self.hist_pos > 0 => {
+                let pos = self.hist_pos - 1;
+                self.history_go(pos);
+            }
+            Key::Down if self.hist_pos < self.inner.as_ref().unwrap().history.len() => {
+                let pos = self.hist_pos + 1;
+                self.history_go(pos);
+            }
+            _ => return,
+        }
+        self.redraw();
+    }
+
+    /// Send pending output. Returns `false` if stdout's queue is full.
+    fn flush_out(&mut self) -> Result<bool> {
+        if self.out.is_empty() {
+            return Ok(true);
+        }
+        let chunk = Bytes::from(::std::mem::take(&mut self.out));
+        let stdout = &mut self.inner.as_mut().unwrap().stdout;
+        match stdout.start_send_chunk(chunk)? {
+            AsyncSink::Ready => Ok(true),
+            AsyncSink::NotReady(x) => {
+                self.out = x.to_vec();
+                Ok(false)
+            }
+        }
+    }
+
+    /// Decode the next key. With `force`, an incomplete sequence (e.g. a lone ESC) is taken as is.
+    fn take_key(&mut self, force: bool) -> Option<Key> {
+        while !self.pending.is_empty() {
+            match keys::parse(&self.pending, force) {
+                Parsed::Event(ev, len) => {
+                    self.pending.drain(..len);
+                    return Some(Key::from_event(&ev));
+                }
+                Parsed::Skip(len) => {
+                    self.pending.drain(..len);
+                }
+                Parsed::Incomplete => return None,
+            }
+        }
+        None
+    }
+
+    /// Handle the keys decoded so far
+    fn handle_pending(&mut self, force: bool) {
+        while self.done.is_none() {
+            match self.take_key(force) {
+                Some(key) => self.handle(key),
+                None => return,
+            }
+        }
+        // Typed ahead, for the next prompt
+        let stdin = &mut self.inner.as_mut().unwrap().stdin;
+        stdin.unread(&self.pending);
+        self.pending.clear();
+    }
+
+    /// Interactive mode: decode keys until the line is finished
+    fn poll_editor(&mut self) -> Poll<(), Error> {
+        loop {
+            if !self.flush_out()? {
+                return Ok(Async::NotReady);
+            }
+            if self.done.is_some() {
+                return Ok(Async::Ready(()));
+            }
+            self.handle_pending(false);
+            if self.done.is_some() {
+                continue;
+            }
+            let read = match self.inner.as_mut().unwrap().stdin.fill_buf() {
+                Ok(buf) => {
+                    self.pending.extend_from_slice(buf);
+                    Ok(buf.len())
+                }
+                Err(e) => Err(e),
+            };
+            let n = match read {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    if self.pending.is_empty() {
+                        return Ok(Async::NotReady);
+                    }
+                    // Rest of an escape sequence, unless it was a lone ESC
+                    let delay = self
+                        .delay
+                        .get_or_insert_with(|| Delay::new(Instant::now() + keys::ESC_TIMEOUT));
+                    try_ready!(delay.poll().map_err(|e| Error::new(ErrorKind::Other, e)));
+                    self.delay = None;
+                    self.handle_pending(true);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            self.inner.as_mut().unwrap().stdin.consume(n);
+            self.delay = None;
+            if n == 0 {
+                self.handle_pending(true);
+                if self.done.is_none() {
+                    self.out.extend_from_slice(b"\r\n");
+                    self.done = Some(if self.line.is_empty() {
+                        PromptResult::Eof
+                    } else {
+                        PromptResult::Line(self.line.iter().collect())
+                    });
+                }
+            }
+        }
+    }
+
+    /// Non-interactive mode: just read up to a newline
+    fn poll_plain(&mut self) -> Poll<(), Error> {
+        let stdin = &mut self.inner.as_mut().unwrap().stdin;
+        loop {
+            let (n, found) = {
+                let buf = match stdin.fill_buf() {
+                    Ok(x) => x,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                    Err(e) => return Err(e),
+                };
+                match buf.iter().position(|&c| c == b'\n') {
+                    Some(i) => {
+                        self.pending.extend_from_slice(&buf[..i]);
+                        (i + 1, true)
+                    }
+                    None => {
+                        self.pending.extend_from_slice(buf);
+                        (buf.len(), buf.is_empty())
+                    }
+                }
+            };
+            stdin.consume(n);
+            if !found {
+                continue;
+            }
+            self.done = Some(if n == 0 && self.pending.is_empty() {
+                PromptResult::Eof
+            } else {
+                if self.pending.last() == Some(&b'\r') {
+                    self.pending.pop();
+                }
+                PromptResult::Line(String::from_utf8_lossy(&self.pending).into_owned())
+            });
+            self.pending.clear();
+            return Ok(Async::Ready(()));
+        }
+    }
+}
+
+impl Future for ReadLine {
+    type Item = (Prompt, PromptResult);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(Prompt, PromptResult), Error> {
+        let interactive = self
+            .inner
+            .as_ref()
+            .expect("polled ReadLine after completion")
+            .is_interactive();
+        if interactive {
+            if !self.started {
+                self.started = true;
+                #[cfg(unix)]
+                {
+                    self.raw = Some(::term::RawModeGuard::enable(0)?);
+                }
+                self.redraw();
+            }
+            try_ready!(self.poll_editor());
+            #[cfg(unix)]
+            {
+                self.raw = None;
+            }
+        } else {
+            try_ready!(self.poll_plain());
+        }
+        let mut prompt = self.inner.take().unwrap();
+        let result = self.done.take().unwrap();
+        if let PromptResult::Line(ref x) = result {
+            prompt.remember(x);
+        }
+        Ok(Async::Ready((prompt, result)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use testutil::{block_on, chunks_reader, SharedBuf};
+    use {threaded_reader, StdioKind};
+
+    fn prompt(input: &[&[u8]]) -> Prompt {
+        let stdout = ::threaded_writer(4, StdioKind::Pipe, SharedBuf::default);
+        Prompt::new(threaded_reader(chunks_reader(input), 1), stdout)
+    }
+
+    /// Feed `input` to the editor as if it was typed, then let the ESC timeout expire
+    fn type_keys(r: &mut ReadLine, input: &[u8]) {
+        r.pending.extend_from_slice(input);
+        r.handle_pending(false);
+        r.handle_pending(true);
+    }
+
+    fn line(r: &ReadLine) -> (String, usize) {
+        (r.line.iter().collect(), r.cursor)
+    }
+
+    #[test]
+    fn editing() {
+        let mut r = prompt(&[]).read_line("> ");
+        type_keys(&mut r, b"hllo\x01\x1b[Ce");
+        assert_eq!(line(&r), ("hello".to_string(), 2));
+        assert!(r.out.ends_with(b"\r> hello\x1b[K\x1b[3D"));
+        type_keys(&mut r, b"\x05\x7f\x1b[D\x15");
+        assert_eq!(line(&r), ("l".to_string(), 0));
+        // Alt+x, F5 and a lone ESC are ignored
+        type_keys(&mut r, "\x1bx\x1b[15~日\x1b".as_bytes());
+        assert_eq!(line(&r), ("日l".to_string(), 1));
+        type_keys(&mut r, b"\x0b\x1b[D\x1b[3~");
+        assert_eq!(line(&r), ("".to_string(), 0));
+        type_keys(&mut r, b"\x04");
+        assert_eq!(r.done, Some(PromptResult::Eof));
+    }
+
+    #[test]
+    fn interrupt_and_typed_ahead() {
+        let mut r = prompt(&[]).read_line("> ");
+        type_keys(&mut r, b"ab\x03cd");
+        assert_eq!(r.done, Some(PromptResult::Interrupted));
+        assert!(r.out.ends_with(b"^C\r\n"));
+        let stdin = &mut r.inner.as_mut().unwrap().stdin;
+        let mut rest = vec![];
+        block_on(|| stdin.read_to_end(&mut rest)).unwrap();
+        assert_eq!(rest, b"cd");
+    }
+
+    #[test]
+    fn decoder() {
+        let cases: &[(&[u8], Key)] = &[
+            (b"\x1b[A", Key::Up),
+            (b"\x1bOB", Key::Down),
+            (b"\x1b[1~", Key::Home),
+            (b"\x1b[8~", Key::End),
+            (b"\x1b[1;5C", Key::Right),
+            (b"\x08", Key::Backspace),
+            (b"\x10", Key::Up),
+            (b"\r", Key::Enter),
+            (b"\t", Key::Ignored),
+            ("é".as_bytes(), Key::Char('é')),
+        ];
+        let mut r = prompt(&[]).read_line("> ");
+        for &(input, key) in cases {
+            r.pending = input.to_vec();
+            assert_eq!(r.take_key(false), Some(key), "{:?}", input);
+            assert!(r.pending.is_empty());
+        }
+        // Incomplete sequences wait for more input, unless forced
+        r.pending = b"\x1b[".to_vec();
+        assert_eq!(r.take_key(false), None);
+        r.pending = b"\x1b".to_vec();
+        assert_eq!(r.take_key(false), None);
+        assert_eq!(r.take_key(true), Some(Key::Ignored));
+        r.pending = b"\xc3".to_vec();
+        assert_eq!(r.take_key(false), None);
+    }
+
+    #[test]
+    fn history() {
+        let mut p = prompt(&[]).max_history(3);
+        for x in &["one", "", "two", "two", "three", "four"] {
+            p.add_history_entry(x.to_string());
+        }
+        assert_eq!(p.history(), ["two", "three", "four"]);
+
+        let mut r = p.read_line("> ");
+        type_keys(&mut r, b"new\x1b[A");
+        assert_eq!(line(&r).0, "four");
+        type_keys(&mut r, b"\x1b[A\x1b[A\x1b[A");
+        assert_eq!(line(&r).0, "two");
+        type_keys(&mut r, b"\x0e");
+        assert_eq!(line(&r).0, "three");
+        type_keys(&mut r, b"\x1b[B\x1b[B\x1b[B");
+        assert_eq!(line(&r), ("new".to_string(), 3));
+    }
+
+    #[test]
+    fn plain_lines() {
+        let p = prompt(&[b"first\r\nsec", b"ond\n\nthird"]);
+        assert!(!p.is_interactive());
+        let mut p = p;
+        let mut got = vec![];
+        loop {
+            let (p_, x) = p.read_line("> ").wait().unwrap();
+            p = p_;
+            if x == PromptResult::Eof {
+                break;
+            }
+            got.push(x);
+        }
+        let lines: Vec<_> = ["first", "second", "", "third"]
+            .iter()
+            .map(|x| PromptResult::Line(x.to_string()))
+            .collect();
+        assert_eq!(got, lines);
+        assert_eq!(p.history(), ["first", "second", "third"]);
+    }
+
+    #[test]
+    fn history_file() {
+        let dir = ::std::env::temp_dir().join(format!("prompt-test-{}", ::std::process::id()));
+        ::std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history");
+        ::std::fs::write(&path, "old\n").unwrap();
+        let p = prompt(&[b"new\n"]).history_file(&path).unwrap();
+        assert_eq!(p.history(), ["old"]);
+        let (p, _) = p.read_line("> ").wait().unwrap();
+        assert_eq!(p.history(), ["old", "new"]);
+        assert_eq!(::std::fs::read_to_string(&path).unwrap(), "old\nnew\n");
+        ::std::fs::remove_dir_all(&dir).unwrap();
+
+        // The line is still returned when the history file cannot be written
+        let p = prompt(&[b"lost?\n"])
+            .history_file(dir.join("history"))
+            .unwrap();
+        let (p, x) = p.read_line("> ").wait().unwrap();
+        assert_eq!(x, PromptResult::Line("lost?".to_string()));
+        assert_eq!(p.history(), ["lost?"]);
+    }
+}
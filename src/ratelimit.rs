@@ -0,0 +1,126 @@
+//! Output rate limiting
+
+use futures::{Async, Future};
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+use ThreadedStdout;
+
+/// Token bucket: `tokens` bytes may be sent right away, refilled at `rate` bytes per second
+/// up to `burst`. A chunk may be sent whenever there is at least one token,
+/// and leaves the bucket in debt if it is bigger. The debt is capped at `burst`.
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+    delay: Option<Delay>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst: u64) -> TokenBucket {
+        let burst = burst.max(1) as f64;
+        TokenBucket {
+            rate: rate as f64,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+            delay: None,
+        }
+    }
+
+    /// Add tokens for the time since the last refill
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    /// Bytes that may be sent now. Zero means the current task is woken up when there are some.
+    pub(crate) fn poll_budget(&mut self) -> Result<usize> {
+        loop {
+            let now = Instant::now();
+            self.refill(now);
+            if self.tokens >= 1.0 {
+                self.delay = None;
+                return Ok(self.tokens as usize);
+            }
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+            let delay = self.delay.get_or_insert_with(|| Delay::new(now + wait));
+            match delay.poll().map_err(|e| Error::new(ErrorKind::Other, e))? {
+                Async::NotReady => return Ok(0),
+                Async::Ready(()) => self.delay = None,
+            }
+        }
+    }
+
+    pub(crate) fn consume(&mut self, n: usize) {
+        self.tokens = (self.tokens - n as f64).max(-self.burst);
+    }
+}
+
+impl ThreadedStdout {
+    /// Limit output to `bytes_per_second` on average, allowing bursts of up to `burst` bytes.
+    ///
+    /// When the budget is exhausted, writes return `WouldBlock` and the task is woken up
+    /// by a timer, so this needs to run within a tokio runtime.
+    ///
+    /// Writes through `std::io::Write` are cut to the budget. Frames, lines and records
+    /// sent by the sinks built on this handle are not split: one is sent whenever there is
+    /// any budget, and may overdraw it by up to `burst` bytes. Chunks bigger than that
+    /// go out faster than the limit.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if `bytes_per_second` is zero.
+    pub fn rate_limit(mut self, bytes_per_second: u64, burst: u64) -> Result<ThreadedStdout> {
+        if bytes_per_second == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "rate limit must be positive",
+            ));
+        }
+        self.limit = Some(TokenBucket::new(bytes_per_second, burst));
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refill() {
+        let mut b = TokenBucket::new(100, 50);
+        let start = b.last;
+        assert_eq!(b.tokens, 50.0);
+        b.consume(30);
+        b.refill(start + Duration::from_millis(100));
+        assert_eq!(b.tokens, 30.0);
+        // Never more than `burst`
+        b.refill(start + Duration::from_secs(10));
+        assert_eq!(b.tokens, 50.0);
+        // A clock going backwards adds nothing
+        b.refill(start);
+        assert_eq!(b.tokens, 50.0);
+    }
+
+    #[test]
+    fn debt() {
+        let mut b = TokenBucket::new(100, 50);
+        let start = b.last;
+        b.consume(80);
+        assert_eq!(b.tokens, -30.0);
+        b.refill(start + Duration::from_millis(500));
+        assert_eq!(b.tokens, 20.0);
+        // A huge chunk leaves at most `burst` of debt
+        b.consume(1_000_000);
+        assert_eq!(b.tokens, -50.0);
+        b.refill(start + Duration::from_secs(1));
+        assert_eq!(b.tokens, 0.0);
+    }
+
+    #[test]
+    fn zero_burst() {
+        let b = TokenBucket::new(100, 0);
+        assert_eq!(b.burst, 1.0);
+    }
+}
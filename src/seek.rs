@@ -0,0 +1,155 @@
+//! Seeking support for `ThreadedStdin` backed by a regular file
+
+use futures::{Async, Future, Poll};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use ThreadedStdin;
+
+/// Reader which may or may not support seeking, as seen by the worker thread
+pub(crate) trait MaybeSeek: Read {
+    fn seek_to(&mut self, pos: SeekFrom) -> Result<u64>;
+}
+
+/// Wrapper to use a non-seekable reader with the worker thread
+pub(crate) struct NotSeekable<R>(pub(crate) R);
+
+impl<R: Read> Read for NotSeekable<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.0.read(buf)
+    }
+}
+impl<R: Read> MaybeSeek for NotSeekable<R> {
+    fn seek_to(&mut self, _pos: SeekFrom) -> Result<u64> {
+        Err(not_seekable())
+    }
+}
+
+/// Wrapper to use a seekable reader with the worker thread
+pub(crate) struct Seekable<R>(pub(crate) R);
+
+impl<R: Read> Read for Seekable<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.0.read(buf)
+    }
+}
+impl<R: Read + Seek> MaybeSeek for Seekable<R> {
+    fn seek_to(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+pub(crate) fn not_seekable() -> Error {
+    Error::new(ErrorKind::Unsupported, "stdin is not seekable")
+}
+
+/// fd 0 accessed as a `File`, bypassing the buffer of `std::io::Stdin`. It is never closed.
+#[cfg(unix)]
+pub(crate) struct Fd0(::std::mem::ManuallyDrop<::std::fs::File>);
+
+#[cfg(unix)]
+impl Fd0 {
+    pub(crate) fn new() -> Fd0 {
+        use std::os::unix::io::FromRawFd;
+        Fd0(::std::mem::ManuallyDrop::new(unsafe {
+            ::std::fs::File::from_raw_fd(0)
+        }))
+    }
+}
+
+#[cfg(unix)]
+impl Read for Fd0 {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+#[cfg(unix)]
+impl Seek for Fd0 {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+/// Future returned by `ThreadedStdin::seek`
+pub struct SeekFuture {
+    inner: Option<ThreadedStdin>,
+    pos: SeekFrom,
+}
+
+impl SeekFuture {
+    pub(crate) fn new(inner: ThreadedStdin, pos: SeekFrom) -> SeekFuture {
+        SeekFuture {
+            inner: Some(inner),
+            pos,
+        }
+    }
+}
+
+impl Future for SeekFuture {
+    type Item = (ThreadedStdin, u64);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(ThreadedStdin, u64), Error> {
+        let pos = {
+            let inner = self
+                .inner
+                .as_mut()
+                .expect("polled SeekFuture after completion");
+            try_ready!(inner.poll_seek(self.pos))
+        };
+        Ok(Async::Ready((self.inner.take().unwrap(), pos)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::poll_fn;
+    use futures::Future;
+    use std::io::{Cursor, ErrorKind, Read, SeekFrom};
+    use testutil::{block_on, chunks_reader};
+    use {threaded_reader, threaded_seekable_reader, ThreadedStdin};
+
+    fn read_n(s: &mut ThreadedStdin, n: usize) -> Vec<u8> {
+        let mut buf = vec![0; n];
+        let got = block_on(|| s.read(&mut buf)).unwrap();
+        buf.truncate(got);
+        buf
+    }
+
+    fn seek(s: &mut ThreadedStdin, pos: SeekFrom) -> ::std::io::Result<u64> {
+        poll_fn(|| s.poll_seek(pos)).wait()
+    }
+
+    #[test]
+    fn seek_around() {
+        let mut s = threaded_seekable_reader(Cursor::new(b"0123456789".to_vec()), 1);
+        assert!(s.is_seekable());
+        assert_eq!(read_n(&mut s, 3), b"012");
+        assert_eq!(seek(&mut s, SeekFrom::Current(2)).unwrap(), 5);
+        assert_eq!(read_n(&mut s, 2), b"56");
+        assert_eq!(seek(&mut s, SeekFrom::Current(-4)).unwrap(), 3);
+        assert_eq!(read_n(&mut s, 1), b"3");
+        assert_eq!(seek(&mut s, SeekFrom::End(-2)).unwrap(), 8);
+        assert_eq!(read_n(&mut s, 10), b"89");
+        assert_eq!(seek(&mut s, SeekFrom::Start(1)).unwrap(), 1);
+        assert_eq!(read_n(&mut s, 2), b"12");
+    }
+
+    #[test]
+    fn seek_before_start() {
+        let mut s = threaded_seekable_reader(Cursor::new(b"0123".to_vec()), 1);
+        assert_eq!(read_n(&mut s, 2), b"01");
+        let e = seek(&mut s, SeekFrom::Current(-3)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        // The position is kept
+        assert_eq!(read_n(&mut s, 2), b"23");
+    }
+
+    #[test]
+    fn not_seekable() {
+        let mut s = threaded_reader(chunks_reader(&[b"abc"]), 1);
+        assert!(!s.is_seekable());
+        let e = seek(&mut s, SeekFrom::Start(0)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+        assert_eq!(read_n(&mut s, 3), b"abc");
+    }
+}
@@ -0,0 +1,333 @@
+//! Splitting stdin into records by a delimiter on the worker thread
+
+use bytes::{Bytes, BytesMut};
+use futures::{Async, Poll, Stream};
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use {Control, Incoming, StdinSource, ThreadedStdin, BIGBUFSIZ};
+
+/// Inner error of the `ErrorKind::InvalidData` error for a record longer than the maximum size
+#[derive(Debug)]
+struct Oversized;
+
+impl fmt::Display for Oversized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("record is longer than the maximum record size")
+    }
+}
+
+impl ::std::error::Error for Oversized {}
+
+fn oversized() -> Error {
+    Error::new(ErrorKind::InvalidData, Oversized)
+}
+
+/// Whether `e` reports a record longer than the maximum size, as opposed to e.g. a read error
+#[cfg(feature = "ndjson")]
+pub(crate) fn is_oversized(e: &Error) -> bool {
+    match e.get_ref() {
+        Some(x) => x.is::<Oversized>(),
+        None => false,
+    }
+}
+
+/// Incremental delimiter scanner. Lives on the async side until the worker thread takes it over.
+pub(crate) struct Splitter {
+    delim: Vec<u8>,
+    max_len: usize,
+    buf: BytesMut,
+    /// This much of `buf` is already known not to contain the delimiter
+    scanned: usize,
+    /// Remainder of an oversized record is being dropped up to the next delimiter
+    skipping: bool,
+}
+
+impl Splitter {
+    fn new(delim: &[u8], max_len: usize) -> Splitter {
+        assert!(!delim.is_empty(), "delimiter must not be empty");
+        Splitter {
+            delim: delim.to_vec(),
+            max_len,
+            buf: BytesMut::new(),
+            scanned: 0,
+            skipping: false,
+        }
+    }
+
+    /// Forget everything buffered, e.g. after a seek
+    pub(crate) fn clear(&mut self) {
+        self.buf.clear();
+        self.scanned = 0;
+        self.skipping = false;
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Next complete record, without the delimiter
+    fn next_record(&mut self) -> Option<Result<Bytes>> {
+        let dl = self.delim.len();
+        loop {
+            let start = self.scanned.saturating_sub(dl - 1);
+            let found = self.buf[start..]
+                .windows(dl)
+                .position(|w| w == &self.delim[..])
+                .map(|i| i + start);
+            match found {
+                Some(i) => {
+                    let mut rec = self.buf.split_to(i + dl);
+                    rec.truncate(i);
+                    self.scanned = 0;
+                    if self.skipping {
+                        self.skipping = false;
+                        continue;
+                    }
+                    if i > self.max_len {
+                        return Some(Err(oversized()));
+                    }
+                    return Some(Ok(rec.freeze()));
+                }
+                None => {
+                    // The tail may be the beginning of a delimiter
+                    let keep = ::std::cmp::min(self.buf.len(), dl - 1);
+                    if self.skipping {
+                        let drop = self.buf.len() - keep;
+                        self.buf.advance(drop);
+                        self.scanned = self.buf.len();
+                        return None;
+                    }
+                    self.scanned = self.buf.len();
+                    if self.buf.len() - keep > self.max_len {
+                        self.skipping = true;
+                        let drop = self.buf.len() - keep;
+                        self.buf.advance(drop);
+                        self.scanned = self.buf.len();
+                        return Some(Err(oversized()));
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Unterminated last record at end of file
+    fn finish(&mut self) -> Option<Result<Bytes>> {
+        let skipping = self.skipping;
+        let rec = self.buf.take();
+        self.clear();
+        if skipping || rec.is_empty() {
+            None
+        } else if rec.len() > self.max_len {
+            Some(Err(oversized()))
+        } else {
+            Some(Ok(rec.freeze()))
+        }
+    }
+
+    /// Used by the worker thread. Empty `data` means end of file.
+    pub(crate) fn feed<F, E>(&mut self, data: &[u8], mut emit: F) -> ::std::result::Result<(), E>
+    where
+        F: FnMut(Incoming) -> ::std::result::Result<(), E>,
+    {
+        let to_incoming = |x: Result<Bytes>| match x {
+            Ok(rec) => Incoming::Data(rec),
+            Err(e) => Incoming::Error(e),
+        };
+        if data.is_empty() {
+            if let Some(x) = self.finish() {
+                emit(to_incoming(x))?;
+            }
+            return emit(Incoming::Eof);
+        }
+        self.push(data);
+        while let Some(x) = self.next_record() {
+            emit(to_incoming(x))?;
+        }
+        Ok(())
+    }
+}
+
+/// Stream of records returned by `ThreadedStdin::split_by` and `ThreadedStdin::split_by_seq`.
+///
+/// Records do not include the delimiter. The last record may be unterminated.
+/// A record longer than the maximum size is reported as an `ErrorKind::InvalidData` error
+/// (once per such record) and skipped; the stream can be polled further after that.
+pub struct Records {
+    inner: ThreadedStdin,
+    /// Scanning is done here until the worker thread takes it over (or always, for `stdin_mmap`)
+    local: Option<Splitter>,
+    /// `Control::Split` is sent to the worker thread
+    requested: bool,
+    /// Worker thread is splitting now; everything incoming is records
+    switched: bool,
+}
+
+impl Records {
+    fn new(mut inner: ThreadedStdin, delim: &[u8], max_len: usize) -> Records {
+        let mut sp = Splitter::new(delim, max_len);
+        if let Some(debt) = inner.debt.take() {
+            sp.push(&debt);
+        }
+        Records {
+            inner,
+            local: Some(sp),
+            requested: false,
+            switched: false,
+        }
+    }
+
+    fn poll_local(&mut self, eof: bool) -> Option<Result<Bytes>> {
+        let sp = self.local.as_mut()?;
+        if eof {
+            sp.finish()
+        } else {
+            sp.next_record()
+        }
+    }
+}
+
+impl Stream for Records {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        loop {
+            if let Some(x) = self.poll_local(false) {
+                return x.map(|rec| Async::Ready(Some(rec)));
+            }
+            if self.inner.pending_eof {
+                self.inner.pending_eof = false;
+                if let Some(x) = self.poll_local(true) {
+                    return x.map(|rec| Async::Ready(Some(rec)));
+                }
+                return Ok(Async::Ready(None));
+            }
+            let w = match self.inner.src {
+                StdinSource::Threaded(ref mut w) => w,
+                #[cfg(unix)]
+                StdinSource::Mapped(ref mut m) => {
+                    self.inner.activity.touch();
+                    let chunk = m.chunk(BIGBUFSIZ);
+                    if chunk.is_empty() {
+                        self.inner.pending_eof = true;
+                    } else if let Some(ref mut sp) = self.local {
+                        sp.push(&chunk);
+                    }
+                    continue;
+                }
+            };
+            if !self.requested {
+                if w.ctl.send(Control::Split).is_err() {
+                    return Err(ErrorKind::BrokenPipe.into());
+                }
+                self.requested = true;
+            }
+            let msg = match w.recv() {
+                Ok(Async::Ready(Some(x))) => x,
+                Ok(Async::Ready(None)) => return Err(ErrorKind::BrokenPipe.into()),
+                Ok(Async::NotReady) => {
+                    self.inner.timeout.check()?;
+                    return Ok(Async::NotReady);
+                }
+                Err(_) => return Err(ErrorKind::Other.into()),
+            };
+            self.inner.timeout.reset();
+            match msg {
+                Incoming::Data(x) => {
+                    if self.switched {
+                        return Ok(Async::Ready(Some(x)));
+                    }
+                    // Raw chunk read before the worker thread switched to splitting
+                    if x.is_empty() {
+                        self.inner.pending_eof = true;
+                    } else if let Some(ref mut sp) = self.local {
+                        sp.push(&x);
+                    }
+                }
+                Incoming::Switched => {
+                    let sp = self
+                        .local
+                        .take()
+                        .expect("Splitter is handed over only once");
+                    if w.ctl.send(Control::Resume(sp)).is_err() {
+                        return Err(ErrorKind::BrokenPipe.into());
+                    }
+                    self.switched = true;
+                }
+                Incoming::Eof => return Ok(Async::Ready(None)),
+                Incoming::Error(e) => return Err(e),
+                Incoming::Seeked(_) => (),
+            }
+        }
+    }
+}
+
+impl ThreadedStdin {
+    /// Turn into a stream of records separated by `delim` byte, e.g. `b'\0'` for `find -print0` output.
+    ///
+    /// Scanning for the delimiter is done on the worker thread, which sends complete records.
+    /// Records longer than `max_record_size` are reported as errors.
+    pub fn split_by(self, delim: u8, max_record_size: usize) -> Records {
+        Records::new(self, &[delim], max_record_size)
+    }
+
+    /// Like `split_by`, but with a multi-byte delimiter, e.g. `b"\r\n"`.
+    ///
+    /// Panics if `delim` is empty.
+    pub fn split_by_seq(self, delim: &[u8], max_record_size: usize) -> Records {
+        Records::new(self, delim, max_record_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `chunks` and then end of file; records as strings, errors as `"!"`
+    fn split(delim: &str, max_len: usize, chunks: &[&str]) -> Vec<String> {
+        let mut sp = Splitter::new(delim.as_bytes(), max_len);
+        let mut out = Vec::new();
+        for chunk in chunks.iter().map(|x| x.as_bytes()).chain(Some(&b""[..])) {
+            sp.feed(chunk, |x| -> ::std::result::Result<(), ()> {
+                out.push(match x {
+                    Incoming::Data(rec) => String::from_utf8(rec.to_vec()).unwrap(),
+                    Incoming::Error(_) => "!".to_string(),
+                    Incoming::Eof => "EOF".to_string(),
+                    _ => unreachable!(),
+                });
+                Ok(())
+            })
+            .unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn splitter() {
+        let table: &[(&str, usize, &[&str], &[&str])] = &[
+            ("\n", 10, &["a\nb\n"], &["a", "b", "EOF"]),
+            ("\n", 10, &["a\n\nb"], &["a", "", "b", "EOF"]),
+            ("\n", 10, &["a", "b\nc", "d"], &["ab", "cd", "EOF"]),
+            ("\n", 10, &[], &["EOF"]),
+            // Delimiter split between chunks
+            ("\r\n", 10, &["a\r", "\nb\r\n"], &["a", "b", "EOF"]),
+            ("\r\n", 10, &["a\rb\r\n"], &["a\rb", "EOF"]),
+            (
+                "abc",
+                10,
+                &["xa", "b", "cy", "ab", "abc"],
+                &["x", "yab", "EOF"],
+            ),
+            // Oversized records are reported once and skipped
+            ("\n", 3, &["abc\nabcd\nx\n"], &["abc", "!", "x", "EOF"]),
+            ("\n", 3, &["ab", "cd", "ef", "gh\nx\n"], &["!", "x", "EOF"]),
+            ("\r\n", 3, &["abcde\r", "\nx\r\n"], &["!", "x", "EOF"]),
+            ("\n", 3, &["abcd"], &["!", "EOF"]),
+            ("\n", 3, &["a\nabcd"], &["a", "!", "EOF"]),
+        ];
+        for &(delim, max_len, chunks, expected) in table {
+            assert_eq!(split(delim, max_len, chunks), expected, "{:?}", chunks);
+        }
+    }
+}
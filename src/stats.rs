@@ -0,0 +1,285 @@
+//! Counters for `ThreadedStdin::stats` and `ThreadedStdout::stats`
+
+use std::any::Any;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use {StdinSource, ThreadedStdin, ThreadedStdout};
+
+/// Where data of a stdin or stdout handle is sitting, and what its worker thread has done so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Chunks in the channel between the worker thread and the async side
+    pub queued_chunks: u64,
+    /// Bytes in those chunks
+    pub queued_bytes: u64,
+    /// Bytes received by the async side, but not yet consumed (stdin only)
+    pub debt_bytes: u64,
+    /// Bytes read from stdin or written to stdout by the worker thread,
+    /// after decompression for stdin and after compression for stdout
+    pub total_bytes: u64,
+    /// Read, write and flush calls the worker thread made on the underlying reader or writer.
+    /// These are not necessarily system calls, as e.g. `std::io::Stdout` is buffered.
+    pub io_calls: u64,
+    /// Time the worker thread spent inside those calls
+    pub blocked_in_io: Duration,
+    /// Time the worker thread spent waiting for room in a full channel (stdin only)
+    pub blocked_on_queue: Duration,
+    /// Same as `is_alive` of the handle
+    pub worker_alive: bool,
+}
+
+/// Counters shared between a worker thread and the async side
+pub(crate) struct Counters {
+    queued_chunks: AtomicU64,
+    queued_bytes: AtomicU64,
+    total_bytes: AtomicU64,
+    io_calls: AtomicU64,
+    io_nanos: AtomicU64,
+    queue_nanos: AtomicU64,
+    alive: AtomicBool,
+    /// Message of the panic which ended the worker thread
+    panic: Mutex<Option<String>>,
+    /// Error which ended the stdout worker thread, not yet reported
+    error: Mutex<Option<Error>>,
+}
+
+impl Counters {
+    pub(crate) fn new() -> Arc<Counters> {
+        Arc::new(Counters {
+            queued_chunks: AtomicU64::new(0),
+            queued_bytes: AtomicU64::new(0),
+            total_bytes: AtomicU64::new(0),
+            io_calls: AtomicU64::new(0),
+            io_nanos: AtomicU64::new(0),
+            queue_nanos: AtomicU64::new(0),
+            alive: AtomicBool::new(true),
+            panic: Mutex::new(None),
+            error: Mutex::new(None),
+        })
+    }
+
+    /// Chunk of `len` bytes is about to be put into the channel
+    pub(crate) fn enqueue(&self, len: usize) {
+        self.queued_chunks.fetch_add(1, Ordering::Relaxed);
+        self.queued_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Chunk of `len` bytes was taken out of the channel (or did not get into it)
+    pub(crate) fn dequeue(&self, len: usize) {
+        self.queued_chunks.fetch_sub(1, Ordering::Relaxed);
+        self.queued_bytes.fetch_sub(len as u64, Ordering::Relaxed);
+    }
+
+    /// Measure a call to the underlying reader or writer
+    pub(crate) fn io<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let ret = f();
+        self.io_calls.fetch_add(1, Ordering::Relaxed);
+        self.io_nanos
+            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        ret
+    }
+
+    /// Measure waiting for room in the channel
+    pub(crate) fn queue_wait<T>(&self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let ret = f();
+        self.queue_nanos
+            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        ret
+    }
+
+    fn transferred(&self, n: usize) {
+        self.total_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Body of a worker thread. Marks the worker as dead when it returns
+    /// and remembers the panic message if it panics.
+    pub(crate) fn run_worker<F: FnOnce()>(&self, f: F) {
+        if let Err(payload) = catch_unwind(AssertUnwindSafe(f)) {
+            if let Ok(mut x) = self.panic.lock() {
+                *x = Some(panic_message(&*payload));
+            }
+        }
+        self.alive.store(false, Ordering::Relaxed);
+    }
+
+    /// Remember the error which ends the worker thread, unless there already is one
+    pub(crate) fn fail(&self, e: Error) {
+        if let Ok(mut x) = self.error.lock() {
+            if x.is_none() {
+                *x = Some(e);
+            }
+        }
+    }
+
+    /// Error which ended the worker thread: its panic, or the error given to `fail`
+    /// (which is reported only once)
+    pub(crate) fn exit_error(&self, what: &str) -> Option<Error> {
+        if let Some(e) = self.panic_error(what) {
+            return Some(e);
+        }
+        match self.error.lock() {
+            Ok(mut x) => x.take(),
+            Err(_) => None,
+        }
+    }
+
+    /// The worker thread is still running
+    pub(crate) fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    /// Error describing the panic of the worker thread, if it panicked
+    pub(crate) fn panic_error(&self, what: &str) -> Option<Error> {
+        let msg = match self.panic.lock() {
+            Ok(x) => x.clone()?,
+            Err(_) => return None,
+        };
+        Some(Error::new(
+            ErrorKind::Other,
+            format!("{} worker thread panicked: {}", what, msg),
+        ))
+    }
+
+    fn snapshot(&self, debt_bytes: u64) -> Stats {
+        Stats {
+            queued_chunks: self.queued_chunks.load(Ordering::Relaxed),
+            queued_bytes: self.queued_bytes.load(Ordering::Relaxed),
+            debt_bytes,
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+            io_calls: self.io_calls.load(Ordering::Relaxed),
+            blocked_in_io: Duration::from_nanos(self.io_nanos.load(Ordering::Relaxed)),
+            blocked_on_queue: Duration::from_nanos(self.queue_nanos.load(Ordering::Relaxed)),
+            worker_alive: self.is_alive(),
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+/// Writer which measures calls to the real output
+pub(crate) struct Counted<W> {
+    pub(crate) inner: W,
+    pub(crate) counters: Arc<Counters>,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let inner = &mut self.inner;
+        let n = self.counters.io(|| inner.write(buf))?;
+        self.counters.transferred(n);
+        Ok(n)
+    }
+    fn flush(&mut self) -> Result<()> {
+        let inner = &mut self.inner;
+        self.counters.io(|| inner.flush())
+    }
+}
+
+/// Read from `r`, measuring the call
+pub(crate) fn counted_read<R: Read>(
+    counters: &Counters,
+    r: &mut R,
+    buf: &mut [u8],
+) -> Result<usize> {
+    let n = counters.io(|| r.read(buf))?;
+    counters.transferred(n);
+    Ok(n)
+}
+
+impl ThreadedStdin {
+    /// Where data is sitting and what the worker thread has done so far.
+    ///
+    /// For memory-mapped stdin (see `stdin_mmap`) there is no worker, so its counters stay zero.
+    pub fn stats(&self) -> Stats {
+        let debt = self.debt.as_ref().map_or(0, |x| x.len() as u64);
+        match self.src {
+            StdinSource::Threaded(ref w) => w.stats.snapshot(debt),
+            #[cfg(unix)]
+            StdinSource::Mapped(_) => Stats {
+                debt_bytes: debt,
+                worker_alive: self.is_alive(),
+                ..Stats::default()
+            },
+        }
+    }
+}
+
+impl ThreadedStdout {
+    /// Where data is sitting and what the worker thread has done so far
+    pub fn stats(&self) -> Stats {
+        self.stats.snapshot(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::poll_fn;
+    use futures::Future;
+    use std::io::{Error, ErrorKind, Read, Result, Write};
+    use testutil::{block_on, chunks_reader, SharedBuf};
+    use tokio_io::AsyncWrite;
+    use {threaded_reader, StdioKind};
+
+    #[test]
+    fn reader() {
+        let mut s = threaded_reader(chunks_reader(&[b"abc", b"de"]), 1);
+        let mut buf = [0; 4];
+        assert_eq!(block_on(|| s.peek(&mut buf)).unwrap(), 4);
+        let st = s.stats();
+        // Whole chunks are kept in the debt
+        assert_eq!((st.total_bytes, st.debt_bytes), (5, 5));
+        assert!(st.io_calls >= 2);
+        assert!(st.worker_alive);
+        let mut rest = vec![];
+        block_on(|| s.read_to_end(&mut rest)).unwrap();
+        assert_eq!(s.stats().debt_bytes, 0);
+    }
+
+    #[test]
+    fn writer() {
+        let out = SharedBuf::default();
+        let out_ = out.clone();
+        let mut w = ::threaded_writer(4, StdioKind::Pipe, move || out_);
+        block_on(|| w.write(b"hello")).unwrap();
+        poll_fn(|| w.shutdown()).wait().unwrap();
+        let st = w.stats();
+        assert_eq!(
+            (st.total_bytes, st.queued_chunks, st.queued_bytes),
+            (5, 0, 0)
+        );
+        assert!(!st.worker_alive);
+        assert_eq!(out.contents(), b"hello");
+    }
+
+    struct Broken;
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> Result<usize> {
+            Err(Error::new(ErrorKind::BrokenPipe, "broken"))
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writer_error() {
+        let mut w = ::threaded_writer(4, StdioKind::Pipe, || Broken);
+        block_on(|| w.write(b"hello")).unwrap();
+        let e = poll_fn(|| w.shutdown()).wait().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BrokenPipe);
+        assert!(!w.stats().worker_alive);
+    }
+}
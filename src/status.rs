@@ -0,0 +1,215 @@
+//! Status line at the bottom of the terminal
+
+use bytes::Bytes;
+use futures::{Async, AsyncSink, Poll};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::time::{Duration, Instant};
+use tokio_io::AsyncWrite;
+use ThreadedStderr;
+
+const ERASE: &[u8] = b"\r\x1b[K";
+
+/// Continuously updated single-line status, like a progress indicator, on `ThreadedStderr`.
+///
+/// Other output (log lines) should be written through the `StatusLine` itself: the status is
+/// erased before the output and drawn again after it once the output ends with a newline.
+/// Redraws are limited to one per `min_interval`; a skipped update is drawn on the next
+/// `set`, write or flush after that.
+///
+/// When stderr is not a terminal, the status is not shown and writes pass through unchanged.
+pub struct StatusLine {
+    inner: ThreadedStderr,
+    enabled: bool,
+    status: String,
+    /// Status currently visible on the screen
+    shown: bool,
+    /// `status` changed since it was last drawn
+    dirty: bool,
+    /// Last output ended with a newline, so the cursor is on an empty line
+    at_line_start: bool,
+    min_interval: Duration,
+    last_draw: Option<Instant>,
+}
+
+impl StatusLine {
+    /// Show status lines on `stderr`
+    pub fn new(stderr: ThreadedStderr) -> StatusLine {
+        StatusLine {
+            enabled: stderr.kind().is_tty(),
+            inner: stderr,
+            status: String::new(),
+            shown: false,
+            dirty: false,
+            at_line_start: true,
+            min_interval: Duration::from_millis(100),
+            last_draw: None,
+        }
+    }
+
+    /// Minimal time between redraws, 100ms by default
+    pub fn min_interval(mut self, interval: Duration) -> StatusLine {
+        self.min_interval = interval;
+        self
+    }
+
+    /// Whether the status is shown at all, i.e. stderr is a terminal
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Replace the status text. Only its first line is used.
+    ///
+    /// Drawing is skipped (and retried later) if it is too early or stderr's queue is full.
+    pub fn set(&mut self, status: &str) -> Result<()> {
+        let status = status.lines().next().unwrap_or("");
+        if status != self.status {
+            self.status = status.to_string();
+            self.dirty = true;
+        }
+        let due = match self.last_draw {
+            Some(t) => t.elapsed() >= self.min_interval,
+            None => true,
+        };
+        if due {
+            self.draw()?;
+        }
+        Ok(())
+    }
+
+    /// Remove the status from the screen
+    pub fn clear(&mut self) {
+        self.status.clear();
+        self.dirty = false;
+        if self.shown {
+            self.inner.send_control_bytes(Bytes::from_static(ERASE));
+            self.shown = false;
+        }
+    }
+
+    /// Remove the status from the screen and get `ThreadedStderr` back
+    pub fn into_inner(mut self) -> ThreadedStderr {
+        self.clear();
+        self.inner
+    }
+
+    /// Status as it should appear on the screen, cut to the terminal width
+    fn render(&self) -> Vec<u8> {
+        #[cfg(unix)]
+        {
+            if let Ok((columns, _)) = ::winsize::size_of_fd(2) {
+                // Writing into the last column may wrap the line on some terminals
+                let max = (columns as usize).saturating_sub(1);
+                if columns > 0 && self.status.chars().count() > max {
+                    let cut: String = self.status.chars().take(max).collect();
+                    return cut.into_bytes();
+                }
+            }
+        }
+        self.status.as_bytes().to_vec()
+    }
+
+    /// Draw the status if it changed, the cursor is on an empty line and the queue has room
+    fn draw(&mut self) -> Result<()> {
+        if !self.enabled || !self.dirty || !self.at_line_start {
+            return Ok(());
+        }
+        let mut chunk = b"\r".to_vec();
+        chunk.extend_from_slice(&self.render());
+        chunk.extend_from_slice(b"\x1b[K");
+        if let AsyncSink::Ready = self.inner.start_send_chunk(Bytes::from(chunk))? {
+            self.dirty = false;
+            self.shown = !self.status.is_empty();
+            self.last_draw = Some(Instant::now());
+        }
+        Ok(())
+    }
+}
+
+impl Write for StatusLine {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if !self.enabled || buf.is_empty() {
+            return self.inner.write(buf);
+        }
+        let mut chunk = Vec::with_capacity(buf.len() + self.status.len() + 8);
+        if self.shown {
+            chunk.extend_from_slice(ERASE);
+        }
+        chunk.extend_from_slice(buf);
+        let at_line_start = buf.last() == Some(&b'\n');
+        let redraw = at_line_start && !self.status.is_empty();
+        if redraw {
+            chunk.extend_from_slice(&self.render());
+        }
+        match self.inner.start_send_chunk(Bytes::from(chunk))? {
+            AsyncSink::Ready => (),
+            AsyncSink::NotReady(_) => return Err(ErrorKind::WouldBlock.into()),
+        }
+        self.at_line_start = at_line_start;
+        self.shown = redraw;
+        if redraw {
+            self.dirty = false;
+            self.last_draw = Some(Instant::now());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.draw()?;
+        self.inner.flush()
+    }
+}
+
+impl AsyncWrite for StatusLine {
+    fn shutdown(&mut self) -> Poll<(), Error> {
+        self.clear();
+        try_ready!(self.inner.shutdown());
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StatusLine;
+    use futures::future::poll_fn;
+    use futures::Future;
+    use std::io::Write;
+    use std::time::Duration;
+    use testutil::{block_on, SharedBuf};
+    use tokio_io::AsyncWrite;
+    use StdioKind;
+
+    fn status(kind: StdioKind) -> (StatusLine, SharedBuf) {
+        let out = SharedBuf::default();
+        let out_ = out.clone();
+        let stderr = ::threaded_writer(4, kind, move || out_);
+        (
+            StatusLine::new(stderr).min_interval(Duration::from_secs(0)),
+            out,
+        )
+    }
+
+    #[test]
+    fn log_lines_around_status() {
+        let (mut s, out) = status(StdioKind::Tty);
+        assert!(s.is_enabled());
+        s.set("working\nignored").unwrap();
+        block_on(|| s.write(b"log\n")).unwrap();
+        s.set("done").unwrap();
+        block_on(|| s.flush()).unwrap();
+        poll_fn(|| s.shutdown()).wait().unwrap();
+        assert_eq!(
+            String::from_utf8(out.contents()).unwrap(),
+            "\rworking\x1b[K\r\x1b[Klog\nworking\rdone\x1b[K\r\x1b[K"
+        );
+    }
+
+    #[test]
+    fn not_a_terminal() {
+        let (mut s, out) = status(StdioKind::Pipe);
+        assert!(!s.is_enabled());
+        s.set("working").unwrap();
+        block_on(|| s.write(b"log\n")).unwrap();
+        poll_fn(|| s.shutdown()).wait().unwrap();
+        assert_eq!(out.contents(), b"log\n");
+    }
+}
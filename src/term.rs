@@ -0,0 +1,310 @@
+//! Terminal mode switching
+
+use std::cell::UnsafeCell;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Once;
+use ThreadedStdin;
+
+/// Mode of a terminal to restore when a guard goes away
+struct Entry {
+    id: usize,
+    /// Duplicate owned by the guard
+    fd: RawFd,
+    /// `(st_dev, st_ino)` of the terminal, to recognize it under different descriptors
+    dev: (::libc::dev_t, ::libc::ino_t),
+    orig: ::libc::termios,
+}
+
+/// Live guards, oldest first, protected by `LOCKED`
+struct Stack(UnsafeCell<Vec<Entry>>);
+unsafe impl Sync for Stack {}
+
+static STACK: Stack = Stack(UnsafeCell::new(Vec::new()));
+static LOCKED: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+static HOOKS: Once = Once::new();
+const SIGNALS: [::libc::c_int; 2] = [::libc::SIGINT, ::libc::SIGTERM];
+/// Previous handlers of `SIGNALS`, written once before installing ours
+struct PrevActions(UnsafeCell<[Option<::libc::sigaction>; 2]>);
+unsafe impl Sync for PrevActions {}
+static PREV_ACTIONS: PrevActions = PrevActions(UnsafeCell::new([None, None]));
+
+/// Run `f` on the stack, waiting for another thread to release it
+fn with_stack<T, F: FnOnce(&mut Vec<Entry>) -> T>(f: F) -> T {
+    while LOCKED
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        ::std::thread::yield_now();
+    }
+    let ret = f(unsafe { &mut *STACK.0.get() });
+    LOCKED.store(false, Ordering::Release);
+    ret
+}
+
+/// Put every terminal back into the mode it had before its outermost guard.
+///
+/// Only async-signal-safe calls here. Gives up if the stack is being changed,
+/// which may be by the very code the signal interrupted.
+fn restore_saved() {
+    if LOCKED
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+    let stack = unsafe { &*STACK.0.get() };
+    for (i, e) in stack.iter().enumerate() {
+        if stack[..i].iter().all(|x| x.dev != e.dev) {
+            unsafe {
+                ::libc::tcsetattr(e.fd, ::libc::TCSANOW, &e.orig);
+            }
+        }
+    }
+    LOCKED.store(false, Ordering::Release);
+}
+
+#[cfg(any(target_os = "linux", target_os = "emscripten", target_os = "redox"))]
+unsafe fn errno_location() -> *mut ::libc::c_int {
+    ::libc::__errno_location()
+}
+#[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+unsafe fn errno_location() -> *mut ::libc::c_int {
+    ::libc::__errno()
+}
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+unsafe fn errno_location() -> *mut ::libc::c_int {
+    ::libc::___errno()
+}
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly"
+))]
+unsafe fn errno_location() -> *mut ::libc::c_int {
+    ::libc::__error()
+}
+
+/// Run `f` from a signal handler without disturbing `errno` of the interrupted code
+pub(crate) fn preserve_errno<T, F: FnOnce() -> T>(f: F) -> T {
+    unsafe {
+        let saved = *errno_location();
+        let ret = f();
+        *errno_location() = saved;
+        ret
+    }
+}
+
+/// Call the handler `prev` was installed with.
+/// Returns `false` if it has none, i.e. it is `SIG_DFL` or `SIG_IGN`.
+pub(crate) unsafe fn call_previous(
+    prev: &::libc::sigaction,
+    sig: ::libc::c_int,
+    info: *mut ::libc::siginfo_t,
+    ctx: *mut ::libc::c_void,
+) -> bool {
+    if prev.sa_sigaction == ::libc::SIG_DFL || prev.sa_sigaction == ::libc::SIG_IGN {
+        return false;
+    }
+    if prev.sa_flags & ::libc::SA_SIGINFO != 0 {
+        let f: extern "C" fn(::libc::c_int, *mut ::libc::siginfo_t, *mut ::libc::c_void) =
+            ::std::mem::transmute(prev.sa_sigaction);
+        f(sig, info, ctx);
+    } else {
+        let f: extern "C" fn(::libc::c_int) = ::std::mem::transmute(prev.sa_sigaction);
+        f(sig);
+    }
+    true
+}
+
+/// Action currently installed for `sig`
+unsafe fn current_action(sig: ::libc::c_int) -> Result<::libc::sigaction> {
+    let mut old: ::libc::sigaction = ::std::mem::zeroed();
+    if ::libc::sigaction(sig, ::std::ptr::null(), &mut old) != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(old)
+}
+
+/// Install `handler` for `sig` with `flags`, keeping `SA_RESTART` if it was set.
+/// Returns the previous action.
+pub(crate) unsafe fn install_handler(
+    sig: ::libc::c_int,
+    handler: extern "C" fn(::libc::c_int, *mut ::libc::siginfo_t, *mut ::libc::c_void),
+    flags: ::libc::c_int,
+) -> Result<::libc::sigaction> {
+    let mut old = current_action(sig)?;
+    let mut sa: ::libc::sigaction = ::std::mem::zeroed();
+    sa.sa_sigaction = handler as ::libc::sighandler_t;
+    sa.sa_flags = flags | ::libc::SA_SIGINFO | (old.sa_flags & ::libc::SA_RESTART);
+    ::libc::sigemptyset(&mut sa.sa_mask);
+    if ::libc::sigaction(sig, &sa, &mut old) != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(old)
+}
+
+extern "C" fn on_signal(
+    sig: ::libc::c_int,
+    info: *mut ::libc::siginfo_t,
+    ctx: *mut ::libc::c_void,
+) {
+    preserve_errno(|| unsafe {
+        let i = SIGNALS.iter().position(|&s| s == sig).unwrap_or(0);
+        let prev = &(*PREV_ACTIONS.0.get())[i];
+        if let Some(ref prev) = *prev {
+            if prev.sa_sigaction == ::libc::SIG_IGN {
+                // The program goes on, and so does raw mode
+                return;
+            }
+        }
+        // Either the previous handler runs, which is likely to exit, or the default
+        // action terminates the process (it does for both of `SIGNALS`)
+        restore_saved();
+        let handled = match *prev {
+            Some(ref prev) => call_previous(prev, sig, info, ctx),
+            None => false,
+        };
+        if !handled {
+            // Die from the signal as if we never handled it
+            ::libc::signal(sig, ::libc::SIG_DFL);
+            ::libc::raise(sig);
+        }
+    })
+}
+
+fn install_hooks() {
+    HOOKS.call_once(|| {
+        let prev = ::std::panic::take_hook();
+        ::std::panic::set_hook(Box::new(move |info| {
+            restore_saved();
+            prev(info);
+        }));
+        unsafe {
+            let prev_actions = &mut *PREV_ACTIONS.0.get();
+            for (i, &sig) in SIGNALS.iter().enumerate() {
+                // `on_signal` may run as soon as it is installed, and must find the previous action
+                if let Ok(old) = current_action(sig) {
+                    prev_actions[i] = Some(old);
+                    let _ = install_handler(sig, on_signal, 0);
+                }
+            }
+        }
+    });
+}
+
+/// Terminal in raw mode: input is delivered byte by byte, without echo and line editing,
+/// and Ctrl-C is just a byte. Output processing is kept, so that `"\n"` still starts a new line.
+///
+/// The original mode is restored when the guard is dropped, when the program panics,
+/// and on SIGINT or SIGTERM unless they are ignored (they then proceed as they would
+/// without the guard).
+/// Guards may be nested and dropped in any order; the terminal ends up in the mode
+/// it had before the first one. Created by `ThreadedStdin::raw_mode`.
+pub struct RawModeGuard {
+    id: usize,
+}
+
+impl RawModeGuard {
+    pub(crate) fn enable(fd: RawFd) -> Result<RawModeGuard> {
+        RawModeGuard::modify(fd, |t| {
+            t.c_iflag &=
+                !(::libc::BRKINT | ::libc::ICRNL | ::libc::INPCK | ::libc::ISTRIP | ::libc::IXON);
+            t.c_lflag &= !(::libc::ECHO | ::libc::ICANON | ::libc::IEXTEN | ::libc::ISIG);
+            t.c_cflag |= ::libc::CS8;
+            t.c_cc[::libc::VMIN] = 1;
+            t.c_cc[::libc::VTIME] = 0;
+        })
+    }
+
+    /// Line mode without echo, except for the final newline
+    pub(crate) fn echo_off(fd: RawFd) -> Result<RawModeGuard> {
+        RawModeGuard::modify(fd, |t| {
+            t.c_lflag &= !::libc::ECHO;
+            t.c_lflag |= ::libc::ECHONL;
+        })
+    }
+
+    /// Apply `f` to the terminal settings, with the same restoring as for raw mode.
+    ///
+    /// The guard keeps its own duplicate of `fd`, so it works after `fd` is closed.
+    fn modify<F: FnOnce(&mut ::libc::termios)>(fd: RawFd, f: F) -> Result<RawModeGuard> {
+        install_hooks();
+        unsafe {
+            let fd = ::libc::fcntl(fd, ::libc::F_DUPFD_CLOEXEC, 0);
+            if fd < 0 {
+                return Err(Error::last_os_error());
+            }
+            let mut st: ::libc::stat = ::std::mem::zeroed();
+            if ::libc::fstat(fd, &mut st) != 0 {
+                let e = Error::last_os_error();
+                ::libc::close(fd);
+                return Err(e);
+            }
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            let ret = with_stack(|stack| {
+                let mut orig: ::libc::termios = ::std::mem::zeroed();
+                if ::libc::tcgetattr(fd, &mut orig) != 0 {
+                    return Err(Error::last_os_error());
+                }
+                let mut t = orig;
+                f(&mut t);
+                if ::libc::tcsetattr(fd, ::libc::TCSANOW, &t) != 0 {
+                    return Err(Error::last_os_error());
+                }
+                stack.push(Entry {
+                    id,
+                    fd,
+                    dev: (st.st_dev, st.st_ino),
+                    orig,
+                });
+                Ok(RawModeGuard { id })
+            });
+            if ret.is_err() {
+                ::libc::close(fd);
+            }
+            ret
+        }
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        let id = self.id;
+        let e = with_stack(|stack| {
+            let i = stack.iter().position(|x| x.id == id)?;
+            let e = stack.remove(i);
+            // A newer guard on the same terminal restores the mode this one would have
+            match stack[i..].iter_mut().find(|x| x.dev == e.dev) {
+                Some(newer) => newer.orig = e.orig,
+                None => unsafe {
+                    ::libc::tcsetattr(e.fd, ::libc::TCSANOW, &e.orig);
+                },
+            }
+            Some(e)
+        });
+        if let Some(e) = e {
+            unsafe {
+                ::libc::close(e.fd);
+            }
+        }
+    }
+}
+
+impl ThreadedStdin {
+    /// Switch the terminal on stdin into raw mode until the returned guard is dropped.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if stdin is not a terminal.
+    pub fn raw_mode(&self) -> Result<RawModeGuard> {
+        if !self.kind.is_tty() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "stdin is not a terminal",
+            ));
+        }
+        RawModeGuard::enable(0)
+    }
+}
@@ -0,0 +1,76 @@
+//! Helpers for unit tests
+
+// Some are only used by tests of optional features
+#![allow(dead_code)]
+
+use futures::future::poll_fn;
+use futures::{Async, Future};
+use std::io::{ErrorKind, Read, Result, Write};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Reader returning the chunks sent to it one by one, and end of file
+/// once the sender is dropped
+pub(crate) struct ChunkReader {
+    rcv: Receiver<Vec<u8>>,
+    rest: Vec<u8>,
+}
+
+pub(crate) fn chunk_reader() -> (Sender<Vec<u8>>, ChunkReader) {
+    let (snd, rcv) = channel();
+    (snd, ChunkReader { rcv, rest: vec![] })
+}
+
+/// `ChunkReader` which has all of `chunks` already
+pub(crate) fn chunks_reader(chunks: &[&[u8]]) -> ChunkReader {
+    let (snd, r) = chunk_reader();
+    for &c in chunks {
+        snd.send(c.to_vec()).unwrap();
+    }
+    r
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.rest.is_empty() {
+            match self.rcv.recv() {
+                Ok(x) => self.rest = x,
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = ::std::cmp::min(buf.len(), self.rest.len());
+        buf[..n].copy_from_slice(&self.rest[..n]);
+        self.rest.drain(..n);
+        Ok(n)
+    }
+}
+
+/// Writer collecting everything written to it
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuf {
+    pub(crate) fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Run `f` within a task until it stops failing with `WouldBlock`
+pub(crate) fn block_on<T, F: FnMut() -> Result<T>>(mut f: F) -> Result<T> {
+    poll_fn(|| match f() {
+        Ok(x) => Ok(Async::Ready(x)),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(Async::NotReady),
+        Err(e) => Err(e),
+    })
+    .wait()
+}
@@ -0,0 +1,152 @@
+//! Read timeouts and idle detection for `ThreadedStdin`
+
+use futures::{Async, Future, Poll};
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+
+/// Time of the last input seen, shared between the worker thread and the async side
+#[derive(Clone)]
+pub(crate) struct Activity(Arc<Mutex<Instant>>);
+
+impl Activity {
+    pub(crate) fn new() -> Activity {
+        Activity(Arc::new(Mutex::new(Instant::now())))
+    }
+    pub(crate) fn touch(&self) {
+        if let Ok(mut x) = self.0.lock() {
+            *x = Instant::now();
+        }
+    }
+    pub(crate) fn last(&self) -> Instant {
+        match self.0.lock() {
+            Ok(x) => *x,
+            Err(e) => *e.into_inner(),
+        }
+    }
+}
+
+/// State of `ThreadedStdin`'s read timeout
+#[derive(Default)]
+pub(crate) struct ReadTimeout {
+    pub(crate) timeout: Option<Duration>,
+    delay: Option<Delay>,
+}
+
+impl ReadTimeout {
+    pub(crate) fn new(timeout: Option<Duration>) -> ReadTimeout {
+        ReadTimeout {
+            timeout,
+            delay: None,
+        }
+    }
+    /// Called when a read would block. Turns `WouldBlock` into `TimedOut`
+    /// if we have been waiting for longer than the timeout.
+    pub(crate) fn check(&mut self) -> Result<(), Error> {
+        let timeout = match self.timeout {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let delay = self
+            .delay
+            .get_or_insert_with(|| Delay::new(Instant::now() + timeout));
+        match delay.poll() {
+            Ok(Async::NotReady) => Ok(()),
+            Ok(Async::Ready(())) => {
+                self.delay = None;
+                Err(ErrorKind::TimedOut.into())
+            }
+            Err(e) => {
+                self.delay = None;
+                Err(Error::new(ErrorKind::Other, e))
+            }
+        }
+    }
+    /// Called when data arrived
+    pub(crate) fn reset(&mut self) {
+        self.delay = None;
+    }
+}
+
+/// Future returned by `ThreadedStdin::idle`
+pub struct Idle {
+    activity: Activity,
+    duration: Duration,
+    delay: Delay,
+}
+
+impl Idle {
+    pub(crate) fn new(activity: Activity, duration: Duration) -> Idle {
+        let delay = Delay::new(activity.last() + duration);
+        Idle {
+            activity,
+            duration,
+            delay,
+        }
+    }
+}
+
+impl Future for Idle {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        loop {
+            try_ready!(self
+                .delay
+                .poll()
+                .map_err(|e| Error::new(ErrorKind::Other, e)));
+            let deadline = self.activity.last() + self.duration;
+            if deadline <= Instant::now() {
+                return Ok(Async::Ready(()));
+            }
+            self.delay.reset(deadline);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{lazy, poll_fn};
+    use futures::Future;
+    use std::io::ErrorKind;
+    use std::time::{Duration, Instant};
+    use testutil::chunk_reader;
+    use threaded_reader;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_io::AsyncRead;
+    use tokio_timer::Delay;
+
+    #[test]
+    fn timed_out_then_data() {
+        let (snd, r) = chunk_reader();
+        let mut s = threaded_reader(r, 1).with_timeout(Duration::from_millis(50));
+        let mut rt = Runtime::new().unwrap();
+        let mut buf = [0; 8];
+        let e = rt.block_on(poll_fn(|| s.poll_read(&mut buf))).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        snd.send(b"late".to_vec()).unwrap();
+        let n = rt.block_on(poll_fn(|| s.poll_read(&mut buf))).unwrap();
+        assert_eq!(&buf[..n], b"late");
+    }
+
+    #[test]
+    fn idle() {
+        let (snd, r) = chunk_reader();
+        let mut s = threaded_reader(r, 1);
+        let mut rt = Runtime::new().unwrap();
+        let start = Instant::now();
+        let idle = s.idle(Duration::from_millis(100));
+        // Input after 60ms postpones the idle moment
+        rt.block_on(lazy(|| {
+            Delay::new(start + Duration::from_millis(60)).map(move |()| snd.send(b"x".to_vec()))
+        }))
+        .unwrap()
+        .unwrap();
+        let mut buf = [0; 1];
+        rt.block_on(poll_fn(|| s.poll_read(&mut buf))).unwrap();
+        rt.block_on(idle).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+}
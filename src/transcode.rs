@@ -0,0 +1,322 @@
+//! Legacy text encodings for stdin and stdout (requires `encoding` cargo feature)
+//!
+//! ```rust,no_run
+//! extern crate futures;
+//! extern crate tokio;
+//! extern crate tokio_io;
+//! extern crate tokio_stdin_stdout;
+//!
+//! use futures::{Future, Stream};
+//! use tokio_stdin_stdout::transcode::Encoding;
+//!
+//! // Convert Windows-1251 (or anything with a BOM) to Shift_JIS
+//! let input = tokio_stdin_stdout::stdin(0).decode(Encoding::for_label(b"windows-1251").unwrap());
+//! let output = tokio_stdin_stdout::stdout(0).encode(Encoding::for_label(b"shift_jis").unwrap());
+//! let f = input
+//!     .fold(output, |o, s| tokio_io::io::write_all(o, s).map(|(o, _)| o))
+//!     .and_then(|o| tokio_io::io::shutdown(o))
+//!     .map(|_| ())
+//!     .map_err(|e| eprintln!("{}", e));
+//! tokio::runtime::current_thread::Runtime::new().unwrap().block_on(f).unwrap();
+//! ```
+
+use bytes::Bytes;
+use encoding_rs::{CoderResult, Decoder, Encoder, UTF_16BE, UTF_16LE};
+use futures::{Async, Poll, Stream};
+use std::io::{Error, ErrorKind, Result, Write};
+use tokio_io::AsyncWrite;
+use {ThreadedStdin, ThreadedStdout};
+
+pub use encoding_rs::Encoding;
+
+/// Stream of UTF-8 text decoded from stdin. Created by `ThreadedStdin::decode`.
+///
+/// Malformed input is replaced with U+FFFD REPLACEMENT CHARACTER.
+pub struct DecodedStdin {
+    inner: ThreadedStdin,
+    decoder: Decoder,
+    eof: bool,
+}
+
+impl DecodedStdin {
+    /// Encoding actually used. May change after the first chunk if it starts with a BOM.
+    pub fn encoding(&self) -> &'static Encoding {
+        self.decoder.encoding()
+    }
+}
+
+impl Stream for DecodedStdin {
+    type Item = String;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, Error> {
+        loop {
+            if self.eof {
+                return Ok(Async::Ready(None));
+            }
+            let chunk = match try_ready!(self.inner.poll()) {
+                Some(x) => x,
+                None => {
+                    self.eof = true;
+                    Bytes::new()
+                }
+            };
+            let mut out = String::new();
+            let mut src = &chunk[..];
+            loop {
+                let need = self.decoder.max_utf8_buffer_length(src.len());
+                out.reserve(need.unwrap_or(src.len() * 3 + 16));
+                let (res, read, _) = self.decoder.decode_to_string(src, &mut out, self.eof);
+                src = &src[read..];
+                if let CoderResult::InputEmpty = res {
+                    break;
+                }
+            }
+            // Nothing complete yet, e.g. half of a UTF-16 code unit
+            if !out.is_empty() {
+                return Ok(Async::Ready(Some(out)));
+            }
+        }
+    }
+}
+
+/// Writer which transcodes UTF-8 into another encoding and sends it to stdout.
+/// Created by `ThreadedStdout::encode`.
+///
+/// Characters not representable in the target encoding are written as HTML numeric
+/// character references, like `&#1234;`. Characters may be split between `write` calls;
+/// data which is not valid UTF-8 is an `ErrorKind::InvalidData` error.
+/// UTF-16 is written without a byte order mark.
+pub struct EncodedStdout {
+    inner: ThreadedStdout,
+    encoding: &'static Encoding,
+    /// Not used for UTF-16, which `encoding_rs` can not encode into
+    encoder: Encoder,
+    /// Beginning of a character split between writes
+    partial: Vec<u8>,
+    /// Encoded chunk which did not fit into the queue yet
+    buffered: Option<Bytes>,
+    /// The encoder has been told about the end of the data
+    finished: bool,
+}
+
+impl EncodedStdout {
+    /// Encoding being written
+    pub fn encoding(&self) -> &'static Encoding {
+        self.encoding
+    }
+
+    /// Get the underlying `ThreadedStdout` back. Buffered data is lost.
+    pub fn into_inner(self) -> ThreadedStdout {
+        self.inner
+    }
+
+    fn encode(&mut self, src: &str, last: bool) {
+        if self.encoding == UTF_16LE || self.encoding == UTF_16BE {
+            self.encode_utf16(src);
+            return;
+        }
+        let mut out = Vec::new();
+        let mut src = src;
+        loop {
+            let need = self
+                .encoder
+                .max_buffer_length_from_utf8_if_no_unmappables(src.len());
+            // Room for a few numeric character references on top of that
+            out.reserve(need.unwrap_or(src.len() * 2) + 64);
+            let (res, read, _) = self.encoder.encode_from_utf8_to_vec(src, &mut out, last);
+            src = &src[read..];
+            if let CoderResult::InputEmpty = res {
+                break;
+            }
+        }
+        if !out.is_empty() {
+            self.buffered = Some(Bytes::from(out));
+        }
+    }
+
+    fn encode_utf16(&mut self, src: &str) {
+        let big_endian = self.encoding == UTF_16BE;
+        let mut out = Vec::with_capacity(src.len() * 2);
+        for unit in src.encode_utf16() {
+            if big_endian {
+                out.extend_from_slice(&unit.to_be_bytes());
+            } else {
+                out.extend_from_slice(&unit.to_le_bytes());
+            }
+        }
+        if !out.is_empty() {
+            self.buffered = Some(Bytes::from(out));
+        }
+    }
+}
+
+impl Write for EncodedStdout {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.inner.send_pending(&mut self.buffered)? {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let mut data = ::std::mem::take(&mut self.partial);
+        data.extend_from_slice(buf);
+        let valid = match ::std::str::from_utf8(&data) {
+            Ok(_) => data.len(),
+            Err(e) => match e.error_len() {
+                None => e.valid_up_to(),
+                Some(_) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "data written to EncodedStdout is not valid UTF-8",
+                    ))
+                }
+            },
+        };
+        self.partial = data.split_off(valid);
+        // Checked by `from_utf8` just now
+        let s = unsafe { ::std::str::from_utf8_unchecked(&data) };
+        self.encode(s, false);
+        self.inner.send_pending(&mut self.buffered)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.inner.send_pending(&mut self.buffered)? {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        self.inner.flush()
+    }
+}
+
+impl AsyncWrite for EncodedStdout {
+    fn shutdown(&mut self) -> Poll<(), Error> {
+        if !self.inner.send_pending(&mut self.buffered)? {
+            return Ok(Async::NotReady);
+        }
+        if !self.partial.is_empty() {
+            self.partial.clear();
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "incomplete UTF-8 character at the end of data written to EncodedStdout",
+            ));
+        }
+        if !self.finished {
+            // Let stateful encodings like ISO-2022-JP return to the initial state
+            self.encode("", true);
+            self.finished = true;
+            if !self.inner.send_pending(&mut self.buffered)? {
+                return Ok(Async::NotReady);
+            }
+        }
+        self.inner.shutdown()
+    }
+}
+
+impl ThreadedStdin {
+    /// Turn into a stream of UTF-8 text decoded from `encoding`.
+    ///
+    /// A UTF-8 or UTF-16 byte order mark at the beginning overrides `encoding` and is removed.
+    pub fn decode(self, encoding: &'static Encoding) -> DecodedStdin {
+        DecodedStdin {
+            inner: self,
+            decoder: encoding.new_decoder(),
+            eof: false,
+        }
+    }
+}
+
+impl ThreadedStdout {
+    /// Turn into a writer which accepts UTF-8 and outputs it in `encoding`
+    pub fn encode(self, encoding: &'static Encoding) -> EncodedStdout {
+        EncodedStdout {
+            inner: self,
+            encoding,
+            encoder: encoding.new_encoder(),
+            partial: Vec::new(),
+            buffered: None,
+            finished: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::{Encoding, ISO_2022_JP, SHIFT_JIS, UTF_16BE, UTF_16LE, WINDOWS_1251};
+    use futures::future::poll_fn;
+    use futures::{Future, Stream};
+    use std::io::Write;
+    use testutil::{block_on, chunks_reader, SharedBuf};
+    use tokio_io::AsyncWrite;
+    use {threaded_reader, StdioKind};
+
+    fn decode(input: &[&[u8]], encoding: &'static Encoding) -> (String, &'static Encoding) {
+        let mut s = threaded_reader(chunks_reader(input), 1).decode(encoding);
+        let text: String = s.by_ref().wait().map(|x| x.unwrap()).collect();
+        (text, s.encoding())
+    }
+
+    fn encode(writes: &[&[u8]], encoding: &'static Encoding) -> Vec<u8> {
+        let out = SharedBuf::default();
+        let out_ = out.clone();
+        let mut w = ::threaded_writer(4, StdioKind::Pipe, move || out_).encode(encoding);
+        for &s in writes {
+            block_on(|| w.write(s)).unwrap();
+        }
+        poll_fn(|| w.shutdown()).wait().unwrap();
+        out.contents()
+    }
+
+    #[test]
+    fn decoding() {
+        let input: &[&[u8]] = &[b"\xcf\xf0\xe8", b"\xe2\xe5\xf2"];
+        assert_eq!(decode(input, WINDOWS_1251), ("Привет".into(), WINDOWS_1251));
+        // BOM overrides the encoding, code units are split between chunks
+        let input: &[&[u8]] = &[b"\xff", b"\xfeh\x00i", b"\x00"];
+        assert_eq!(decode(input, WINDOWS_1251), ("hi".into(), UTF_16LE));
+        let input: &[&[u8]] = &[b"\xfe\xff\x00h"];
+        assert_eq!(decode(input, WINDOWS_1251), ("h".into(), UTF_16BE));
+        // Malformed input is replaced
+        let input: &[&[u8]] = &[b"\x82\xa0\x82"];
+        assert_eq!(decode(input, SHIFT_JIS), ("あ\u{fffd}".into(), SHIFT_JIS));
+    }
+
+    #[test]
+    fn round_trip() {
+        // "日本èé" with `é` split between writes
+        let sjis = encode(
+            &[b"\xe6\x97\xa5\xe6\x9c\xac\xc3\xa8\xc3", b"\xa9"],
+            SHIFT_JIS,
+        );
+        assert_eq!(decode(&[&sjis], SHIFT_JIS).0, "日本&#232;&#233;");
+        let cp1251 = encode(&["Привет, ".as_bytes(), "мир".as_bytes()], WINDOWS_1251);
+        assert_eq!(
+            decode(&[&cp1251[..3], &cp1251[3..]], WINDOWS_1251).0,
+            "Привет, мир"
+        );
+    }
+
+    #[test]
+    fn utf16() {
+        // "a😀" with the emoji split between writes; no BOM is written
+        let writes: &[&[u8]] = &[b"a\xf0\x9f", b"\x98\x80"];
+        let le = encode(writes, UTF_16LE);
+        assert_eq!(le, b"a\x00\x3d\xd8\x00\xde");
+        let be = encode(writes, UTF_16BE);
+        assert_eq!(be, b"\x00a\xd8\x3d\xde\x00");
+        assert_eq!(decode(&[&le[..3], &le[3..]], UTF_16LE).0, "a😀");
+        assert_eq!(decode(&[&be], UTF_16BE).0, "a😀");
+    }
+
+    #[test]
+    fn stateful_shutdown() {
+        let out = SharedBuf::default();
+        let out_ = out.clone();
+        let mut w = ::threaded_writer(4, StdioKind::Pipe, move || out_).encode(ISO_2022_JP);
+        block_on(|| w.write("a日本".as_bytes())).unwrap();
+        poll_fn(|| w.shutdown()).wait().unwrap();
+        // The switch back to ASCII is written once, also if shutdown is polled again
+        poll_fn(|| w.shutdown()).wait().unwrap();
+        assert_eq!(out.contents(), b"a\x1b$BF|K\\\x1b(B");
+    }
+}
@@ -0,0 +1,86 @@
+//! The controlling terminal as a duplex handle
+
+use futures::Poll;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, Error, Read, Result, Write};
+use std::os::unix::io::AsRawFd;
+use term::RawModeGuard;
+use tokio_io::{AsyncRead, AsyncWrite};
+use {threaded_reader_impl, threaded_writer, StdioKind, ThreadedStdin, ThreadedStdout};
+
+/// Reading and writing handle to the controlling terminal. Created by `tty`.
+///
+/// Works like a `ThreadedStdin` and `ThreadedStdout` glued together, with one worker thread each.
+pub struct ThreadedTty {
+    stdin: ThreadedStdin,
+    stdout: ThreadedStdout,
+    /// For terminal settings
+    file: File,
+}
+
+impl ThreadedTty {
+    /// Switch the terminal into raw mode until the returned guard is dropped.
+    ///
+    /// The guard keeps working after `split` or after this handle is dropped.
+    pub fn raw_mode(&self) -> Result<RawModeGuard> {
+        RawModeGuard::enable(self.file.as_raw_fd())
+    }
+
+    /// Split into separate reading and writing handles
+    pub fn split(self) -> (ThreadedStdin, ThreadedStdout) {
+        (self.stdin, self.stdout)
+    }
+}
+
+/// Open `/dev/tty`, the terminal of this process even when stdin and stdout are redirected.
+///
+/// Fails if there is no controlling terminal, e.g. in daemons and cron jobs.
+/// Note that `ThreadedStdin::raw_mode` of a handle from `split` works on stdin,
+/// not on this terminal; use `ThreadedTty::raw_mode` instead.
+pub fn tty(queue_size: usize) -> Result<ThreadedTty> {
+    let file = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    let kind = StdioKind::of_fd(file.as_raw_fd());
+    let r = file.try_clone()?;
+    let w = file.try_clone()?;
+    let worker = threaded_reader_impl(queue_size, kind.chunk_size(), None, move || {
+        ::seek::NotSeekable(r)
+    });
+    Ok(ThreadedTty {
+        stdin: ThreadedStdin::from_worker(worker, kind),
+        stdout: threaded_writer(queue_size, kind, move || w),
+        file,
+    })
+}
+
+impl Read for ThreadedTty {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.stdin.read(buf)
+    }
+}
+
+/// `fill_buf` returns `WouldBlock` when there is no data yet, like `read`.
+impl BufRead for ThreadedTty {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        self.stdin.fill_buf()
+    }
+    fn consume(&mut self, amt: usize) {
+        self.stdin.consume(amt)
+    }
+}
+
+impl AsyncRead for ThreadedTty {}
+
+impl Write for ThreadedTty {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.stdout.write(buf)
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.stdout.flush()
+    }
+}
+
+impl AsyncWrite for ThreadedTty {
+    fn shutdown(&mut self) -> Poll<(), Error> {
+        self.stdout.shutdown()
+    }
+}
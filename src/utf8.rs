@@ -0,0 +1,260 @@
+//! Decoding stdin as UTF-8 text, with characters never split between chunks
+
+use bytes::Bytes;
+use futures::{Async, Poll, Stream};
+use std::io::{Error, ErrorKind, Result};
+use {concat, ThreadedStdin};
+
+/// What to do with bytes which are not valid UTF-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidUtf8 {
+    /// Return an `ErrorKind::InvalidData` error, once per invalid sequence.
+    /// The stream can be polled further after that.
+    Error,
+    /// Replace each invalid sequence with U+FFFD REPLACEMENT CHARACTER, like `String::from_utf8_lossy`
+    Replace,
+    /// Decode each invalid byte as Latin-1, i.e. as the character with the same code point.
+    /// Valid UTF-8 is still decoded as UTF-8, so this suits mostly-UTF-8 input with stray Latin-1.
+    Latin1Fallback,
+}
+
+/// Stream of UTF-8 text returned by `ThreadedStdin::utf8_chunks`
+pub struct Utf8Chunks {
+    inner: ThreadedStdin,
+    policy: InvalidUtf8,
+    /// Not yet decoded input
+    rest: Bytes,
+    /// `rest` ends with an incomplete character
+    incomplete: bool,
+    eof: bool,
+    /// Offset of `rest` in the input, for error messages
+    offset: u64,
+    /// Still need to look for a byte order mark
+    strip_bom: bool,
+}
+
+impl Utf8Chunks {
+    /// Drop U+FEFF BYTE ORDER MARK at the beginning of input, if present
+    pub fn strip_bom(mut self) -> Utf8Chunks {
+        self.strip_bom = true;
+        self
+    }
+
+    /// Turn into a stream of individual characters
+    pub fn chars(self) -> Chars {
+        Chars {
+            inner: self,
+            chunk: String::new(),
+            pos: 0,
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.rest.advance(n);
+        self.offset += n as u64;
+    }
+
+    /// Decode as much of `rest` as possible
+    fn decode(&mut self) -> Result<String> {
+        let mut out = String::new();
+        loop {
+            let (valid, invalid) = match ::std::str::from_utf8(&self.rest) {
+                Ok(s) => {
+                    out.push_str(s);
+                    (s.len(), None)
+                }
+                Err(e) => {
+                    let valid = e.valid_up_to();
+                    // Checked by `from_utf8` just now
+                    out.push_str(unsafe { ::std::str::from_utf8_unchecked(&self.rest[..valid]) });
+                    match e.error_len() {
+                        Some(n) => (valid, Some(n)),
+                        // Truncated character at the end of input
+                        None if self.eof => (valid, Some(self.rest.len() - valid)),
+                        None => {
+                            self.consume(valid);
+                            self.incomplete = true;
+                            return Ok(out);
+                        }
+                    }
+                }
+            };
+            self.consume(valid);
+            let n = match invalid {
+                Some(n) => n,
+                None => return Ok(out),
+            };
+            match self.policy {
+                InvalidUtf8::Error if !out.is_empty() => return Ok(out),
+                InvalidUtf8::Error => {
+                    let msg = format!("invalid UTF-8 sequence at byte offset {}", self.offset);
+                    self.consume(n);
+                    return Err(Error::new(ErrorKind::InvalidData, msg));
+                }
+                InvalidUtf8::Replace => out.push('\u{FFFD}'),
+                InvalidUtf8::Latin1Fallback => {
+                    out.extend(self.rest[..n].iter().map(|&b| char::from(b)))
+                }
+            }
+            self.consume(n);
+        }
+    }
+}
+
+impl Stream for Utf8Chunks {
+    type Item = String;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<String>, Error> {
+        loop {
+            if !self.eof && (self.rest.is_empty() || self.incomplete) {
+                match try_ready!(self.inner.poll()) {
+                    Some(x) if self.rest.is_empty() => self.rest = x,
+                    Some(x) => self.rest = concat(&self.rest, &x),
+                    None => self.eof = true,
+                }
+                self.incomplete = false;
+            }
+            let mut s = self.decode()?;
+            if self.strip_bom && !s.is_empty() {
+                self.strip_bom = false;
+                if s.starts_with('\u{FEFF}') {
+                    s.drain(..3);
+                }
+            }
+            if !s.is_empty() {
+                return Ok(Async::Ready(Some(s)));
+            }
+            if self.eof && self.rest.is_empty() {
+                return Ok(Async::Ready(None));
+            }
+        }
+    }
+}
+
+/// Stream of characters returned by `ThreadedStdin::chars`
+pub struct Chars {
+    inner: Utf8Chunks,
+    chunk: String,
+    pos: usize,
+}
+
+impl Chars {
+    /// Get the chunk stream back. Characters decoded, but not yet returned, are lost.
+    pub fn into_inner(self) -> Utf8Chunks {
+        self.inner
+    }
+}
+
+impl Stream for Chars {
+    type Item = char;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<char>, Error> {
+        loop {
+            if let Some(c) = self.chunk[self.pos..].chars().next() {
+                self.pos += c.len_utf8();
+                return Ok(Async::Ready(Some(c)));
+            }
+            match try_ready!(self.inner.poll()) {
+                Some(x) => {
+                    self.chunk = x;
+                    self.pos = 0;
+                }
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+impl ThreadedStdin {
+    /// Turn into a stream of `String`s, each cut on a character boundary.
+    ///
+    /// Invalid input is handled according to `policy`.
+    pub fn utf8_chunks(self, policy: InvalidUtf8) -> Utf8Chunks {
+        Utf8Chunks {
+            inner: self,
+            policy,
+            rest: Bytes::new(),
+            incomplete: false,
+            eof: false,
+            offset: 0,
+            strip_bom: false,
+        }
+    }
+
+    /// Turn into a stream of characters. Use `utf8_chunks(..).strip_bom().chars()` to drop a byte order mark.
+    pub fn chars(self, policy: InvalidUtf8) -> Chars {
+        self.utf8_chunks(policy).chars()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InvalidUtf8;
+    use futures::Stream;
+    use std::io::ErrorKind;
+    use testutil::chunks_reader;
+    use {threaded_reader, ThreadedStdin};
+
+    fn stdin(chunks: &[&[u8]]) -> ThreadedStdin {
+        threaded_reader(chunks_reader(chunks), 1)
+    }
+
+    fn chunks(input: &[&[u8]], policy: InvalidUtf8) -> Vec<String> {
+        let s = stdin(input).utf8_chunks(policy);
+        s.wait().map(|x| x.unwrap()).collect()
+    }
+
+    #[test]
+    fn split_characters() {
+        let input: &[&[u8]] = &[b"a\xc3", b"\xa9b\xf0\x9f", b"\x98", b"\x80!"];
+        assert_eq!(chunks(input, InvalidUtf8::Error), ["a", "éb", "😀!"]);
+        let s = stdin(input).chars(InvalidUtf8::Error);
+        let chars: String = s.wait().map(|x| x.unwrap()).collect();
+        assert_eq!(chars, "aéb😀!");
+    }
+
+    #[test]
+    fn byte_order_mark() {
+        let input: &[&[u8]] = &[b"\xef\xbb", b"\xbfhi\xef\xbb\xbf"];
+        assert_eq!(chunks(input, InvalidUtf8::Error), ["\u{feff}hi\u{feff}"]);
+        let s = stdin(input).utf8_chunks(InvalidUtf8::Error).strip_bom();
+        let r: Vec<String> = s.wait().map(|x| x.unwrap()).collect();
+        assert_eq!(r, ["hi\u{feff}"]);
+    }
+
+    #[test]
+    fn invalid_error() {
+        let input: &[&[u8]] = &[b"ab\xffc", b"d\xc3"];
+        let r: Vec<_> = stdin(input)
+            .utf8_chunks(InvalidUtf8::Error)
+            .wait()
+            .collect();
+        assert_eq!(r.len(), 5);
+        assert_eq!(r[0].as_ref().unwrap(), "ab");
+        let e = r[1].as_ref().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "invalid UTF-8 sequence at byte offset 2");
+        assert_eq!(r[2].as_ref().unwrap(), "c");
+        assert_eq!(r[3].as_ref().unwrap(), "d");
+        // Truncated character at the end
+        let e = r[4].as_ref().unwrap_err();
+        assert_eq!(e.to_string(), "invalid UTF-8 sequence at byte offset 5");
+    }
+
+    #[test]
+    fn invalid_replace() {
+        let input: &[&[u8]] = &[b"ab\xff\xfec", b"d\xc3"];
+        let r = chunks(input, InvalidUtf8::Replace);
+        assert_eq!(r.concat(), "ab\u{fffd}\u{fffd}cd\u{fffd}");
+    }
+
+    #[test]
+    fn invalid_latin1_fallback() {
+        // Valid UTF-8 is still decoded as such, also across chunks
+        let input: &[&[u8]] = &[b"ab\xff\xe9c\xc3", b"\xa9d\xc3"];
+        let r = chunks(input, InvalidUtf8::Latin1Fallback);
+        assert_eq!(r.concat(), "abÿécédÃ");
+    }
+}
@@ -0,0 +1,150 @@
+//! Terminal size and SIGWINCH notifications
+
+use futures::sync::mpsc::{channel, Receiver, Sender};
+use futures::{Async, Poll, Stream};
+use std::cell::UnsafeCell;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Mutex, Once};
+
+/// Size of the terminal on stdout as `(columns, rows)`
+pub fn terminal_size() -> Result<(u16, u16)> {
+    size_of_fd(1)
+}
+
+pub(crate) fn size_of_fd(fd: RawFd) -> Result<(u16, u16)> {
+    unsafe {
+        let mut ws: ::libc::winsize = ::std::mem::zeroed();
+        if ::libc::ioctl(fd, ::libc::TIOCGWINSZ, &mut ws) != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok((ws.ws_col, ws.ws_row))
+    }
+}
+
+/// Write end of the pipe the signal handler pokes, -1 before setup
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+static SETUP: Once = Once::new();
+/// Previous SIGWINCH handler, written once before installing ours
+struct PrevAction(UnsafeCell<Option<::libc::sigaction>>);
+unsafe impl Sync for PrevAction {}
+static PREV_ACTION: PrevAction = PrevAction(UnsafeCell::new(None));
+static LISTENERS: Mutex<Vec<Sender<()>>> = Mutex::new(Vec::new());
+
+extern "C" fn on_sigwinch(
+    sig: ::libc::c_int,
+    info: *mut ::libc::siginfo_t,
+    ctx: *mut ::libc::c_void,
+) {
+    ::term::preserve_errno(|| {
+        let fd = PIPE.load(Ordering::SeqCst);
+        if fd >= 0 {
+            unsafe {
+                // Full pipe means a notification is pending anyway
+                ::libc::write(fd, b"\0".as_ptr() as *const _, 1);
+            }
+        }
+        unsafe {
+            if let Some(ref prev) = *PREV_ACTION.0.get() {
+                ::term::call_previous(prev, sig, info, ctx);
+            }
+        }
+    })
+}
+
+fn setup() -> Result<()> {
+    let mut result = Ok(());
+    SETUP.call_once(|| result = install());
+    result?;
+    if PIPE.load(Ordering::SeqCst) < 0 {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "SIGWINCH handler could not be installed",
+        ));
+    }
+    Ok(())
+}
+
+fn install() -> Result<()> {
+    unsafe {
+        let mut fds: [RawFd; 2] = [-1, -1];
+        if ::libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(Error::last_os_error());
+        }
+        for &fd in &fds {
+            ::libc::fcntl(fd, ::libc::F_SETFD, ::libc::FD_CLOEXEC);
+        }
+        ::libc::fcntl(fds[1], ::libc::F_SETFL, ::libc::O_NONBLOCK);
+        let (rd, wr) = (fds[0], fds[1]);
+        ::std::thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            loop {
+                let n = ::libc::read(rd, buf.as_mut_ptr() as *mut _, buf.len());
+                if n < 0 && Error::last_os_error().kind() == ::std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                if n <= 0 {
+                    break;
+                }
+                let mut listeners = match LISTENERS.lock() {
+                    Ok(x) => x,
+                    Err(e) => e.into_inner(),
+                };
+                // A full channel already has a notification pending
+                listeners.retain_mut(|s| match s.try_send(()) {
+                    Ok(()) => true,
+                    Err(e) => e.is_full(),
+                });
+            }
+        });
+        PIPE.store(wr, Ordering::SeqCst);
+        match ::term::install_handler(::libc::SIGWINCH, on_sigwinch, ::libc::SA_RESTART) {
+            Ok(old) => *PREV_ACTION.0.get() = Some(old),
+            Err(e) => {
+                PIPE.store(-1, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Stream of new terminal sizes as `(columns, rows)`. Created by `resize_events`.
+///
+/// Several resizes in quick succession may be reported as one.
+pub struct ResizeEvents {
+    rcv: Receiver<()>,
+}
+
+impl Stream for ResizeEvents {
+    type Item = (u16, u16);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<(u16, u16)>, Error> {
+        match self.rcv.poll() {
+            Ok(Async::Ready(Some(()))) => terminal_size().map(|x| Async::Ready(Some(x))),
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(()) => Err(Error::new(
+                ErrorKind::Other,
+                "resize notification channel failed",
+            )),
+        }
+    }
+}
+
+/// Get notified when the terminal window changes size (on SIGWINCH).
+///
+/// Notifications come from a background thread through the same kind of channel
+/// `ThreadedStdin` uses, so the stream can be `select`ed with stdin in one task.
+/// Other SIGWINCH handlers installed before the first call are still called.
+pub fn resize_events() -> Result<ResizeEvents> {
+    setup()?;
+    let (snd, rcv) = channel(0);
+    match LISTENERS.lock() {
+        Ok(mut x) => x.push(snd),
+        Err(e) => e.into_inner().push(snd),
+    }
+    Ok(ResizeEvents { rcv })
+}